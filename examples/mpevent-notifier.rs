@@ -8,18 +8,16 @@ use mpevent::coordinator::Coordinator;
 use mpevent::participant::Participant;
 
 fn main() {
-    let mut coordinator = Coordinator::open_existing("example1");
+    let mut coordinator = Coordinator::open_existing("example1").unwrap();
     let participant_id = coordinator.add_participant("test_participant2").unwrap();
 
     let mut waitable = coordinator.add_event(participant_id, "test_event").unwrap();
-    waitable.post_with_value(1, 1000);
+    waitable.trigger(1000).unwrap();
 
     println!("Event test_event posted");
 
-    let mut publisher = Participant::new("test_notifier", "example1");
-    publisher
-        .trigger_event("test_event", std::u32::MAX)
-        .unwrap();
+    let mut publisher = Participant::new("test_notifier", "example1").unwrap();
+    publisher.trigger_event("test_event", u32::MAX).unwrap();
 
     let _ = coordinator
         .add_event(participant_id, "test_event2")
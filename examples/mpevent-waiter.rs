@@ -2,11 +2,11 @@ use mpevent::coordinator::Coordinator;
 use mpevent::participant::Participant;
 
 fn main() {
-    let mut coordinator = Coordinator::new_clean("example1");
+    let mut coordinator = Coordinator::new_clean("example1").unwrap();
     let fixed_participant_id = coordinator.add_participant("test_participant").unwrap();
 
     let handle = std::thread::spawn(move || {
-        let mut sub = Participant::new("test_subscriber", "example1");
+        let mut sub = Participant::new("test_subscriber", "example1").unwrap();
 
         sub.set_on_create_participant_callback(move |participant_id: u64| {
             println!(
@@ -20,7 +20,7 @@ fn main() {
     });
 
     let handle2 = std::thread::spawn(move || {
-        let mut sub = Participant::new("test_subscriber", "example1");
+        let mut sub = Participant::new("test_subscriber2", "example1").unwrap();
 
         sub.set_on_create_event_callback(move |event_id: u64| {
             println!(" ######## New event id {} created and is not me!", event_id);
@@ -33,14 +33,9 @@ fn main() {
         .add_event(fixed_participant_id, "test_event")
         .unwrap();
 
-    let wait_time = libc::timespec {
-        tv_sec: 15,
-        tv_nsec: 0,
-    };
-
-    waitable.wait_with_timeout(0, wait_time);
-    let has_timeout = waitable.get_futex_value() == 0;
-    waitable.set_futex_value(0);
+    let has_timeout = !waitable
+        .wait_timeout(std::time::Duration::from_secs(15))
+        .unwrap();
     println!("Event received. Has timeout? {}", has_timeout);
 
     handle.join().unwrap();
@@ -0,0 +1,245 @@
+use mpevent::coordinator::Coordinator;
+use mpevent::group::{collect_stale_groups_in, find_group_in, list_groups_in, GroupOptions};
+use mpevent::journal;
+use mpevent::observer::Observer;
+use mpevent::watch::Watcher;
+use mpevent::NO_PARTICIPANT;
+
+use std::io::Write;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: mpevent [--root <dir>] [--token <token>] <command>
+
+Commands:
+    list-groups                              List the groups on the host
+    show <group>                             Show the participants and events of a group
+    trigger <group> <event>                  Trigger an event, waking all its waiters
+    wait <group> <event> [--timeout <secs>]  Wait until an event is triggered
+    watch <group> [--json]                   Print the changes of a group as they happen
+    journal <group> [--output <file>]        Print or save the journal of a group
+    gc                                       Remove the groups left behind by dead processes";
+
+struct Args {
+    options: GroupOptions,
+    timeout: Option<Duration>,
+    json: bool,
+    output: Option<String>,
+    command: Vec<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        options: GroupOptions::new(),
+        timeout: None,
+        json: false,
+        output: None,
+        command: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |option: &str| {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", option))
+        };
+        match arg.as_str() {
+            "--root" => parsed.options.set_root(&value("--root")?),
+            "--token" => parsed.options.set_join_token(&value("--token")?),
+            "--timeout" => {
+                let secs: f64 = value("--timeout")?
+                    .parse()
+                    .map_err(|_| String::from("Invalid timeout"))?;
+                let timeout = Duration::try_from_secs_f64(secs)
+                    .map_err(|_| String::from("Invalid timeout"))?;
+                parsed.timeout = Some(timeout);
+            }
+            "--json" => parsed.json = true,
+            "--output" => parsed.output = Some(value("--output")?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => parsed.command.push(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+fn list_groups(options: &GroupOptions) {
+    println!(
+        "{:<32} {:>8} {:>12} {:>6}  STATE",
+        "GROUP", "ATTACHED", "PARTICIPANTS", "EVENTS"
+    );
+    for group in list_groups_in(options) {
+        let state = if group.is_persistent() {
+            "persistent"
+        } else if group.is_stale() {
+            "stale"
+        } else {
+            "live"
+        };
+        println!(
+            "{:<32} {:>8} {:>12} {:>6}  {}",
+            group.get_name(),
+            group.get_attach_count(),
+            group.get_number_of_participants(),
+            group.get_number_of_events(),
+            state
+        );
+    }
+}
+
+fn show(options: &GroupOptions, group: &str) -> Result<(), String> {
+    // Read-only, the group is not attached to nor locked
+    let observer = Observer::open(group, options)?;
+    let info = find_group_in(options, group).ok_or("Group not found")?;
+    println!("Group:      {}", group);
+    println!("Attached:   {}", info.get_attach_count());
+    println!("Persistent: {}", info.is_persistent());
+
+    let participants: Vec<_> = observer.participants().collect();
+    println!();
+    println!(
+        "{:>4} {:<32} {:>8} {:>6}  STATE",
+        "ID", "PARTICIPANT", "PID", "UID"
+    );
+    for participant in &participants {
+        let state = if !participant.is_alive() {
+            "dead"
+        } else if participant.is_stale() {
+            "stale"
+        } else {
+            "alive"
+        };
+        println!(
+            "{:>4} {:<32} {:>8} {:>6}  {}",
+            participant.get_id(),
+            participant.get_name(),
+            participant.get_pid(),
+            participant.get_uid(),
+            state
+        );
+    }
+
+    let prefix = group.to_string() + "_";
+    println!();
+    println!("{:>4} {:<32} {:<32} SIGNALED", "ID", "EVENT", "OWNER");
+    for event in observer.events() {
+        let owner = match event.get_owner() {
+            NO_PARTICIPANT => String::from("-"),
+            owner => participants
+                .iter()
+                .find(|p| p.get_id() == owner)
+                .map(|p| p.get_name())
+                .unwrap_or_else(|| owner.to_string()),
+        };
+        let name = event.get_name();
+        println!(
+            "{:>4} {:<32} {:<32} {}",
+            event.get_id(),
+            name.strip_prefix(&prefix).unwrap_or(&name),
+            owner,
+            event.get_futex_value() != 0
+        );
+    }
+    Ok(())
+}
+
+// The CLI never creates events nor registers itself as a participant, so
+// only events anyone may trigger can be triggered from it
+fn trigger(options: &GroupOptions, group: &str, event_name: &str) -> Result<(), String> {
+    let mut coordinator = Coordinator::open(group, options)?;
+    if coordinator.find_event(event_name).is_none() {
+        return Err(format!("Event {} not found in group {}", event_name, group));
+    }
+    coordinator.open_event(event_name)?.trigger(u32::MAX)?;
+    println!("Event {} triggered", event_name);
+    Ok(())
+}
+
+// Observes the event, leaving the trigger to its regular waiters
+fn wait(
+    options: &GroupOptions,
+    group: &str,
+    event_name: &str,
+    timeout: Option<Duration>,
+) -> Result<(), String> {
+    let observer = Observer::open(group, options)?;
+    if !observer.wait_event(event_name, timeout)? {
+        return Err(String::from("Timeout"));
+    }
+    println!("Event {} received", event_name);
+    Ok(())
+}
+
+// Runs until interrupted or stdout is closed
+fn watch(options: &GroupOptions, group: &str, json: bool) -> Result<(), String> {
+    let mut watcher = Watcher::open(group, options)?;
+    let mut stdout = std::io::stdout();
+    watcher.run(|change| {
+        let line = if json {
+            change.to_json()
+        } else {
+            change.to_text()
+        };
+        writeln!(stdout, "{}", line).is_ok() && stdout.flush().is_ok()
+    });
+    Ok(())
+}
+
+fn print_journal(options: &GroupOptions, group: &str, output: Option<&str>) -> Result<(), String> {
+    let observer = Observer::open(group, options)?;
+    let records = observer.journal();
+    match output {
+        Some(path) => {
+            journal::dump(&records, path)?;
+            println!("{} records written to {}", records.len(), path);
+        }
+        None => {
+            for record in &records {
+                println!("{}", record.to_text());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn gc(options: &GroupOptions) {
+    for name in collect_stale_groups_in(options) {
+        println!("Removed {}", name);
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let command: Vec<&str> = args.command.iter().map(|c| c.as_str()).collect();
+    match command.as_slice() {
+        ["list-groups"] => list_groups(&args.options),
+        ["show", group] => show(&args.options, group)?,
+        ["trigger", group, event] => trigger(&args.options, group, event)?,
+        ["wait", group, event] => wait(&args.options, group, event, args.timeout)?,
+        ["watch", group] => watch(&args.options, group, args.json)?,
+        ["journal", group] => print_journal(&args.options, group, args.output.as_deref())?,
+        ["gc"] => gc(&args.options),
+        _ => return Err(String::from(USAGE)),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("mpevent: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if args.command.is_empty() || args.command[0] == "help" {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("mpevent: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
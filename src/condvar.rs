@@ -0,0 +1,201 @@
+use crate::lock::SharedMutexGuard;
+use crate::segment::Segment;
+use crate::MAX_LOCK_NAME_SIZE;
+use crate::WAKE_ALL;
+
+use rufutex::rufutex::SharedFutex;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// C representation
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Condition {
+    id: u64,
+    mutex_id: u64,
+    sequence: u32,
+    name: [u8; MAX_LOCK_NAME_SIZE],
+}
+
+impl Default for Condition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Condition {
+    pub fn new() -> Self {
+        Condition {
+            id: 0,
+            mutex_id: 0,
+            sequence: 0,
+            name: [0; MAX_LOCK_NAME_SIZE],
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8(vname).unwrap()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_mutex_id(&self) -> u64 {
+        self.mutex_id
+    }
+
+    pub(crate) fn init(&mut self, id: u64, name: &str, mutex_id: u64) -> Result<(), &'static str> {
+        if name.len() >= MAX_LOCK_NAME_SIZE {
+            return Err("Name too long");
+        }
+        *self = Condition::new();
+        self.id = id;
+        self.mutex_id = mutex_id;
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+}
+
+pub struct Condvar {
+    // Keeps the mapping holding the condition alive
+    _segment: Rc<Segment>,
+    condition: *mut Condition,
+    futex: SharedFutex,
+}
+
+impl Condvar {
+    pub(crate) fn new(segment: Rc<Segment>, condition: *mut Condition) -> Self {
+        let ptr_sequence = unsafe { std::ptr::addr_of_mut!((*condition).sequence) };
+        Condvar {
+            _segment: segment,
+            condition,
+            futex: SharedFutex::new(ptr_sequence as *mut libc::c_void),
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        unsafe { (*self.condition).get_name() }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        unsafe { (*self.condition).get_id() }
+    }
+
+    fn sequence(&self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(std::ptr::addr_of_mut!((*self.condition).sequence)) }
+    }
+
+    fn check_guard(&self, guard: &SharedMutexGuard) -> Result<(), String> {
+        let mutex_id = unsafe { (*self.condition).mutex_id };
+        if guard.get_mutex_id() != mutex_id {
+            return Err(String::from("Condvar is bound to a different mutex"));
+        }
+        Ok(())
+    }
+
+    pub fn wait<'a>(
+        &mut self,
+        guard: SharedMutexGuard<'a>,
+    ) -> Result<SharedMutexGuard<'a>, String> {
+        self.check_guard(&guard)?;
+
+        // Read the sequence while the mutex is still held so a notify issued
+        // after the unlock changes it and the futex wait returns immediately.
+        let sequence = self.sequence().load(Ordering::SeqCst);
+        let mutex = guard.unlock();
+        self.futex.wait(sequence);
+        Ok(mutex.lock())
+    }
+
+    pub fn wait_while<'a>(
+        &mut self,
+        mut guard: SharedMutexGuard<'a>,
+        mut condition: impl FnMut() -> bool,
+    ) -> Result<SharedMutexGuard<'a>, String> {
+        while condition() {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    // Returns the guard and whether the wait timed out
+    pub fn wait_timeout<'a>(
+        &mut self,
+        guard: SharedMutexGuard<'a>,
+        timeout: std::time::Duration,
+    ) -> Result<(SharedMutexGuard<'a>, bool), String> {
+        self.check_guard(&guard)?;
+
+        let timeout_spec = libc::timespec {
+            tv_sec: timeout.as_secs() as i64,
+            tv_nsec: timeout.subsec_nanos() as i64,
+        };
+
+        let sequence = self.sequence().load(Ordering::SeqCst);
+        let mutex = guard.unlock();
+        let ret = self.futex.wait_with_timeout(sequence, timeout_spec);
+        let timed_out =
+            ret != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT);
+        Ok((mutex.lock(), timed_out))
+    }
+
+    pub fn notify_one(&mut self) {
+        self.sequence().fetch_add(1, Ordering::SeqCst);
+        self.futex.post(1);
+    }
+
+    pub fn notify_all(&mut self) {
+        self.sequence().fetch_add(1, Ordering::SeqCst);
+        self.futex.post(WAKE_ALL);
+    }
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_condvar() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    let mut coordinator = Coordinator::new("test_condvar").unwrap();
+    let participant_id = coordinator.add_participant("test_condvar_owner").unwrap();
+    let mut mutex = coordinator
+        .mutex(participant_id, "test_condvar_mutex")
+        .unwrap();
+    let mut condvar = coordinator.condvar("test_condvar", &mutex).unwrap();
+    assert_eq!(coordinator.get_number_of_condvars(), 1);
+
+    let other_mutex = coordinator
+        .mutex(participant_id, "test_other_mutex")
+        .unwrap();
+    assert!(coordinator.condvar("test_condvar", &other_mutex).is_err());
+
+    let ready = Arc::new(AtomicBool::new(false));
+    let ready_thread = ready.clone();
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_condvar").unwrap();
+        let mut mutex = coordinator.mutex(1, "test_condvar_mutex").unwrap();
+        let mut condvar = coordinator.condvar("test_condvar", &mutex).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let _guard = mutex.lock();
+        ready_thread.store(true, Ordering::SeqCst);
+        condvar.notify_all();
+    });
+
+    let guard = mutex.lock();
+    let guard = condvar
+        .wait_while(guard, || !ready.load(Ordering::SeqCst))
+        .unwrap();
+    assert!(ready.load(Ordering::SeqCst));
+
+    let (_guard, timed_out) = condvar
+        .wait_timeout(guard, std::time::Duration::from_millis(50))
+        .unwrap();
+    assert!(timed_out);
+    handle.join().unwrap();
+
+    let _ = coordinator.close(true);
+}
@@ -1,15 +1,48 @@
-use crate::event::Event;
-use log::{debug, error};
+use crate::condvar::{Condition, Condvar};
+use crate::counter::{Counter, CounterKind, SharedCounter, SharedGauge};
+use crate::election::{load_term, term_futex, Role, LEADER_LIVENESS_POLL};
+use crate::event::{Event, EventAccess, EventHandle};
+use crate::group::{GroupInfo, GroupOptions};
+use crate::hash::fnv1a;
+use crate::heartbeat::monotonic_ns;
+use crate::journal::{Journal, JournalOp, JournalRecord};
+use crate::kv::{kv_event_name, KeyValue};
+use crate::lock::{Lock, LockKind, SharedMutex, SharedRwLock};
+use crate::segment::{self, Segment};
+use crate::state::{check_layout, Channel, StateChannel, StateType};
+use log::debug;
 use rufutex::rufutex::SharedFutex;
-use rushm::posixaccessor;
 
+use std::collections::HashMap;
 use std::fs;
+use std::io::Read;
+use std::os::fd::{OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::path::Path;
-
+use std::rc::Rc;
+
+use crate::BUILTIN_EVENTS;
+use crate::ERROR_GROUP_NOT_FOUND;
+use crate::ERROR_PERMISSION_DENIED;
+use crate::ERROR_QUOTA_EXCEEDED;
+use crate::MAX_ATTACHED_PIDS;
+use crate::MAX_CONDVARS;
+use crate::MAX_COUNTERS;
 use crate::MAX_EVENTS;
+use crate::MAX_GROUP_NAME_SIZE;
+use crate::MAX_KV_ENTRIES;
+use crate::MAX_LOCKS;
 use crate::MAX_PARTICIPANTS;
 use crate::MAX_PARTICIPANT_NAME_SIZE;
-use crate::{BUILTIN_EVENT_NEW_EVENT, BUILTIN_EVENT_NEW_PARTICIPANT};
+use crate::MAX_ROLES;
+use crate::MAX_STATE_CHANNELS;
+use crate::NO_PARTICIPANT;
+use crate::WAKE_ALL;
+use crate::{
+    BUILTIN_EVENT_LEADER_CHANGED, BUILTIN_EVENT_NEW_EVENT, BUILTIN_EVENT_NEW_PARTICIPANT,
+    BUILTIN_EVENT_PARTICIPANT_STALE,
+};
+use crate::{MAX_EXE_NAME_SIZE, MAX_LABEL_SIZE, MAX_PARTICIPANT_LABELS};
 
 // C representation
 #[repr(C)]
@@ -17,6 +50,19 @@ use crate::{BUILTIN_EVENT_NEW_EVENT, BUILTIN_EVENT_NEW_PARTICIPANT};
 pub struct Participant {
     id: u64,
     name: [u8; MAX_PARTICIPANT_NAME_SIZE],
+    pid: u32,
+    uid: u32,
+    stale: u32,
+    join_time_ns: u64,
+    heartbeat_ns: u64,
+    heartbeat_period_ms: u64,
+    stale_threshold_ms: u64,
+    reclaim_token_hash: u64,
+    // When not set the group default applies
+    event_quota_set: u32,
+    event_quota: u32,
+    exe: [u8; MAX_EXE_NAME_SIZE],
+    labels: [[u8; MAX_LABEL_SIZE]; MAX_PARTICIPANT_LABELS],
 }
 
 impl Participant {
@@ -24,323 +70,1742 @@ impl Participant {
         Participant {
             id: 0,
             name: [0; MAX_PARTICIPANT_NAME_SIZE],
+            pid: 0,
+            uid: 0,
+            stale: 0,
+            join_time_ns: 0,
+            heartbeat_ns: 0,
+            heartbeat_period_ms: 0,
+            stale_threshold_ms: 0,
+            reclaim_token_hash: 0,
+            event_quota_set: 0,
+            event_quota: 0,
+            exe: [0; MAX_EXE_NAME_SIZE],
+            labels: [[0; MAX_LABEL_SIZE]; MAX_PARTICIPANT_LABELS],
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn get_uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn get_exe(&self) -> String {
+        let vexe: Vec<u8> = self.exe.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8_lossy(&vexe).to_string()
+    }
+
+    pub fn get_join_time(&self) -> std::time::SystemTime {
+        std::time::UNIX_EPOCH + std::time::Duration::from_nanos(self.join_time_ns)
+    }
+
+    pub fn get_labels(&self) -> Vec<(String, String)> {
+        let mut labels = Vec::new();
+        for label in self.labels.iter() {
+            let vlabel: Vec<u8> = label.iter().take_while(|&&c| c != 0).cloned().collect();
+            if vlabel.is_empty() {
+                continue;
+            }
+            let label = String::from_utf8(vlabel).unwrap();
+            if let Some((key, value)) = label.split_once('=') {
+                labels.push((key.to_string(), value.to_string()));
+            }
         }
+        labels
+    }
+
+    pub fn get_label(&self, key: &str) -> Option<String> {
+        self.get_labels()
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    fn set_label(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if key.is_empty() || key.contains('=') {
+            return Err(String::from("Invalid label key"));
+        }
+        let label = key.to_string() + "=" + value;
+        if label.len() >= MAX_LABEL_SIZE {
+            return Err(String::from("Label too long"));
+        }
+
+        let mut slot = None;
+        for (i, (k, _)) in self.get_labels().iter().enumerate() {
+            if k == key {
+                slot = Some(i);
+                break;
+            }
+        }
+        if slot.is_none() {
+            slot = self.labels.iter().position(|l| l[0] == 0);
+        }
+        if slot.is_none() {
+            return Err(String::from("Max number of labels reached"));
+        }
+
+        let slot = &mut self.labels[slot.unwrap()];
+        *slot = [0; MAX_LABEL_SIZE];
+        slot[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(())
+    }
+
+    fn set_process_info(&mut self) {
+        self.pid = std::process::id();
+        self.uid = unsafe { libc::getuid() };
+        self.join_time_ns = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let exe = std::env::current_exe()
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+            .unwrap_or_default();
+        let exe = exe.as_bytes();
+        let len = exe.len().min(MAX_EXE_NAME_SIZE - 1);
+        self.exe = [0; MAX_EXE_NAME_SIZE];
+        self.exe[..len].copy_from_slice(&exe[..len]);
     }
 
     pub fn get_name(&self) -> String {
         let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
-        let name = String::from_utf8(vname).unwrap();
-        name
+        String::from_utf8(vname).unwrap()
+    }
+
+    pub fn is_alive(&self) -> bool {
+        is_process_alive(self.pid)
+    }
+
+    pub fn has_heartbeat(&self) -> bool {
+        self.stale_threshold_ms > 0
+    }
+
+    pub fn get_heartbeat_period(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.heartbeat_period_ms)
+    }
+
+    pub fn get_stale_threshold(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.stale_threshold_ms)
+    }
+
+    // Time since the last heartbeat, None if heartbeats are not enabled
+    pub fn get_heartbeat_age(&self) -> Option<std::time::Duration> {
+        if !self.has_heartbeat() {
+            return None;
+        }
+        let now = monotonic_ns();
+        Some(std::time::Duration::from_nanos(
+            now.saturating_sub(self.heartbeat_ns),
+        ))
+    }
+
+    // Stale as of the last watchdog check
+    pub fn is_stale(&self) -> bool {
+        self.stale != 0
+    }
+}
+
+pub(crate) fn is_process_alive(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+impl Default for Participant {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+// Identifies group segments when scanning the shared memory root
+const DIRECTORY_MAGIC: u32 = 0x4d50_4556;
+const DIRECTORY_VERSION: u32 = 1;
+
 // C representation
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
-struct Directory {
+pub(crate) struct Directory {
+    magic: u32,
+    version: u32,
+    // Lets groups opened from a descriptor find out their name
+    name: [u8; MAX_GROUP_NAME_SIZE],
+    lock: u32,
+    // Number of coordinators mapping the group, the last one out unlinks it
+    // unless the group is persistent
+    attach_count: u32,
+    persistent: u32,
+    // Maximum number of events owned by a participant, 0 for no limit
+    default_event_quota: u32,
+    owner_uid: u32,
+    same_uid_only: u32,
+    // Hash of the join token, 0 when the group has none
+    join_token_hash: u64,
+    // One slot per attached coordinator, 0 when free
+    attached_pids: [u32; MAX_ATTACHED_PIDS],
+    builtin_events: [u32; BUILTIN_EVENTS.len()],
     last_participant_id: u64,
     last_event_id: u64,
+    last_lock_id: u64,
+    last_condvar_id: u64,
+    last_counter_id: u64,
+    last_state_channel_id: u64,
+    last_role_id: u64,
     participants: [Participant; MAX_PARTICIPANTS],
     events: [Event; MAX_EVENTS],
-    events_owners: [u64; MAX_EVENTS],
+    locks: [Lock; MAX_LOCKS],
+    condvars: [Condition; MAX_CONDVARS],
+    counters: [Counter; MAX_COUNTERS],
+    kv: [KeyValue; MAX_KV_ENTRIES],
+    state_channels: [Channel; MAX_STATE_CHANNELS],
+    roles: [Role; MAX_ROLES],
+    journal: Journal,
 }
 
 impl Directory {
     pub fn new() -> Self {
         Directory {
+            magic: DIRECTORY_MAGIC,
+            version: DIRECTORY_VERSION,
+            name: [0; MAX_GROUP_NAME_SIZE],
+            lock: 0,
+            attach_count: 0,
+            persistent: 0,
+            default_event_quota: 0,
+            owner_uid: 0,
+            same_uid_only: 0,
+            join_token_hash: 0,
+            attached_pids: [0; MAX_ATTACHED_PIDS],
+            builtin_events: [0; BUILTIN_EVENTS.len()],
             last_participant_id: 0,
             last_event_id: 0,
-            participants: [Participant {
-                id: 0,
-                name: [0; MAX_PARTICIPANT_NAME_SIZE],
-            }; MAX_PARTICIPANTS],
+            last_lock_id: 0,
+            last_condvar_id: 0,
+            last_counter_id: 0,
+            last_state_channel_id: 0,
+            last_role_id: 0,
+            participants: [Participant::new(); MAX_PARTICIPANTS],
             events: [Event::new(); MAX_EVENTS],
-            events_owners: [0; MAX_EVENTS],
+            locks: [Lock::new(); MAX_LOCKS],
+            condvars: [Condition::new(); MAX_CONDVARS],
+            counters: [Counter::new(); MAX_COUNTERS],
+            kv: [KeyValue::new(); MAX_KV_ENTRIES],
+            state_channels: [Channel::new(); MAX_STATE_CHANNELS],
+            roles: [Role::new(); MAX_ROLES],
+            journal: Journal::new(),
+        }
+    }
+
+    // The counters are clamped as the segment may have been written by
+    // anyone with access to it
+    pub(crate) fn participant_count(&self) -> u64 {
+        self.last_participant_id.min(MAX_PARTICIPANTS as u64)
+    }
+
+    pub(crate) fn event_count(&self) -> u64 {
+        self.last_event_id.min(MAX_EVENTS as u64)
+    }
+
+    pub(crate) fn participants_snapshot(&self) -> Vec<Participant> {
+        self.participants[..self.participant_count() as usize].to_vec()
+    }
+
+    pub(crate) fn events_snapshot(&self) -> Vec<Event> {
+        self.events[..self.event_count() as usize].to_vec()
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        self.magic == DIRECTORY_MAGIC && self.version == DIRECTORY_VERSION
+    }
+
+    pub(crate) fn get_name(&self) -> String {
+        let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8_lossy(&vname).to_string()
+    }
+
+    fn events_owned(&self, participant_id: u64) -> u64 {
+        self.events[..self.event_count() as usize]
+            .iter()
+            .filter(|e| e.get_owner() == participant_id)
+            .count() as u64
+    }
+
+    fn event_quota(&self, participant_id: u64) -> Option<u32> {
+        if participant_id >= self.participant_count() {
+            return None;
+        }
+        let participant = &self.participants[participant_id as usize];
+        if participant.event_quota_set != 0 {
+            return Some(participant.event_quota);
+        }
+        if self.default_event_quota != 0 {
+            return Some(self.default_event_quota);
+        }
+        None
+    }
+
+    // Futex words of the builtin events, in BUILTIN_EVENTS order
+    pub(crate) fn get_builtin_words(&self) -> &[u32] {
+        &self.builtin_events
+    }
+
+    pub(crate) fn get_event(&self, id: u64) -> Option<&Event> {
+        if id >= self.event_count() {
+            return None;
+        }
+        Some(&self.events[id as usize])
+    }
+
+    // Resets the group keeping the processes still mapping the segment counted
+    fn reset(&mut self, name: &str, options: &GroupOptions) {
+        let mut fresh = Directory::new();
+        fresh.magic = 0;
+        fresh.name[..name.len()].copy_from_slice(name.as_bytes());
+        fresh.owner_uid = unsafe { libc::geteuid() };
+        fresh.same_uid_only = options.is_same_uid_only() as u32;
+        fresh.join_token_hash = options.get_join_token_hash().unwrap_or(0);
+        Journal::set_enabled(&mut fresh.journal, options.is_journal());
+        // Processes that died while attached are not counted anymore
+        for (slot, pid) in fresh.attached_pids.iter_mut().zip(self.attached_pids) {
+            if pid != 0 && is_process_alive(pid) {
+                *slot = pid;
+                fresh.attach_count += 1;
+            }
+        }
+
+        // The lock word belongs to the process resetting the group
+        let lock = std::mem::offset_of!(Directory, lock);
+        let lock_end = lock + std::mem::size_of::<u32>();
+        let size = std::mem::size_of::<Directory>();
+        unsafe {
+            let src = &fresh as *const Directory as *const u8;
+            let dst = self as *mut Directory as *mut u8;
+            std::ptr::copy_nonoverlapping(src, dst, lock);
+            std::ptr::copy_nonoverlapping(src.add(lock_end), dst.add(lock_end), size - lock_end);
+        }
+    }
+
+    // Same uid only groups must be owned by the caller's user, both in the
+    // header and on the segment itself
+    pub(crate) fn check_access(&self, segment: &Segment, uid: u32) -> Result<(), String> {
+        if self.is_valid()
+            && self.same_uid_only != 0
+            && (self.owner_uid != uid || segment.get_owner_uid() != uid)
+        {
+            return Err(String::from(ERROR_PERMISSION_DENIED));
+        }
+        Ok(())
+    }
+
+    fn check_join_token(&self, token_hash: Option<u64>) -> Result<(), String> {
+        if self.join_token_hash != 0 && token_hash != Some(self.join_token_hash) {
+            return Err(String::from(ERROR_PERMISSION_DENIED));
+        }
+        Ok(())
+    }
+
+    fn attach_pid(&mut self, pid: u32) {
+        if let Some(slot) = self.attached_pids.iter_mut().find(|p| **p == 0) {
+            *slot = pid;
+        }
+        self.attach_count += 1;
+    }
+
+    fn detach_pid(&mut self, pid: u32) {
+        if let Some(slot) = self.attached_pids.iter_mut().find(|p| **p == pid) {
+            *slot = 0;
         }
+        self.attach_count = self.attach_count.saturating_sub(1);
+    }
+
+    // Participants can only be acted on behalf of by their own process
+    fn is_caller(&self, participant_id: u64) -> bool {
+        participant_id < self.participant_count()
+            && self.participants[participant_id as usize].get_pid() == std::process::id()
+    }
+
+    fn has_live_attachments(&self) -> bool {
+        self.attached_pids
+            .iter()
+            .any(|pid| *pid != 0 && is_process_alive(*pid))
+    }
+
+    // Attached processes and participants that are still running
+    fn live_pids(&self) -> Vec<u32> {
+        let mut pids: Vec<u32> = self
+            .attached_pids
+            .iter()
+            .cloned()
+            .chain(self.participants_snapshot().iter().map(|p| p.get_pid()))
+            .filter(|pid| is_process_alive(*pid))
+            .collect();
+        pids.sort();
+        pids.dedup();
+        pids
+    }
+
+    fn group_info(&self, name: &str) -> GroupInfo {
+        GroupInfo::new(
+            name,
+            self.attach_count,
+            self.persistent != 0,
+            self.participant_count(),
+            self.event_count(),
+            self.live_pids(),
+        )
     }
 }
 
-fn clean_shared_files(path: &str, mem_path: &str) {
-    // First unlink all the files under /dev/shm/
-    let shm_path = Path::new(path);
-    if let Ok(entries) = fs::read_dir(shm_path) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if let Ok(file_name) = entry.file_name().into_string() {
-                    if file_name.contains(mem_path) {
-                        let file_path = shm_path.join(file_name);
-                        let _ = fs::remove_file(file_path);
-                    }
-                }
+// Reads the directory of a group without mapping it, None if the file is not a group
+fn read_directory(file_path: &Path) -> Option<Box<Directory>> {
+    let mut file = fs::File::open(file_path).ok()?;
+    if file.metadata().ok()?.len() < std::mem::size_of::<Directory>() as u64 {
+        return None;
+    }
+
+    let mut directory = Box::<Directory>::new_uninit();
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(
+            directory.as_mut_ptr() as *mut u8,
+            std::mem::size_of::<Directory>(),
+        )
+    };
+    file.read_exact(bytes).ok()?;
+    // Every field is plain data, so any byte pattern is a valid directory
+    let directory = unsafe { directory.assume_init() };
+    if !directory.is_valid() {
+        return None;
+    }
+    Some(directory)
+}
+
+// Journal of the group mapped by a segment, the directory is at its start
+pub(crate) fn segment_journal(segment: &Segment) -> *mut Journal {
+    let directory = segment.as_ptr() as *mut Directory;
+    unsafe { std::ptr::addr_of_mut!((*directory).journal) }
+}
+
+// Name of the group mapped by a segment, which prefixes its event names
+pub(crate) fn segment_group_name(segment: &Segment) -> String {
+    let directory = segment.as_ptr() as *const Directory;
+    unsafe { (*directory).get_name() }
+}
+
+pub(crate) fn read_group_info(options: &GroupOptions, name: &str) -> Option<GroupInfo> {
+    let directory = read_directory(&options.directory().join(name))?;
+    Some(directory.group_info(name))
+}
+
+// Unlinks the group if no process is using it anymore. The check is repeated
+// with the directory lock held so a process attaching meanwhile is seen.
+pub(crate) fn remove_group_if_stale(options: &GroupOptions, name: &str) -> bool {
+    let directory = read_directory(&options.directory().join(name));
+    if directory.is_none() || !directory.unwrap().group_info(name).is_stale() {
+        return false;
+    }
+
+    let ret = Segment::open(options, name, std::mem::size_of::<Directory>(), false);
+    if ret.is_err() {
+        return false;
+    }
+    let segment = ret.unwrap();
+    let ptr_data = segment.as_ptr() as *mut Directory;
+    let ptr_lock = unsafe { std::ptr::addr_of_mut!((*ptr_data).lock) };
+    let mut mutex = SharedFutex::new(ptr_lock as *mut libc::c_void);
+
+    mutex.lock();
+    let stale = unsafe { (*ptr_data).is_valid() && (*ptr_data).group_info(name).is_stale() };
+    let removed = stale && segment::unlink(options.get_root(), name).is_ok();
+    mutex.unlock(1);
+    removed
+}
+
+// Only the group segment itself, other groups may be named after it
+fn clean_shared_files(shm_path: &Path, mem_path: &str) {
+    let _ = fs::remove_file(shm_path.join(mem_path));
+}
+
+// Checked before taking the directory lock, names are stored NUL terminated
+fn check_participant_name(name: &str) -> Result<(), String> {
+    if name.len() >= MAX_PARTICIPANT_NAME_SIZE {
+        return Err(String::from("Name too long"));
+    }
+    Ok(())
+}
+
+// Where a group can be joined again from, sendable between threads
+pub(crate) enum GroupLocation {
+    Named(String, GroupOptions),
+    Fd(OwnedFd),
+}
+
+impl GroupLocation {
+    pub(crate) fn open(self) -> Result<Coordinator, String> {
+        match self {
+            GroupLocation::Named(mem_path, options) => Coordinator::open(&mem_path, &options),
+            GroupLocation::Fd(fd) => Coordinator::from_fd(fd),
+        }
+    }
+}
+
+pub struct Coordinator {
+    mem_path: String,
+    options: GroupOptions,
+    directory: *mut Directory,
+    segment: Rc<Segment>,
+    mutex: rufutex::rufutex::SharedFutex,
+    // Keyed by event id and the participant the handle was obtained for
+    event_handles: HashMap<(u64, u64), EventHandle>,
+    attached: bool,
+}
+
+impl Coordinator {
+    // Runs `prepare` on the directory and attaches to the group in the same
+    // critical section. Nothing is attached when `prepare` fails.
+    fn attach(
+        mem_path: &str,
+        options: &GroupOptions,
+        segment: Segment,
+        prepare: impl FnOnce(&mut Directory) -> Result<(), String>,
+    ) -> Result<Self, String> {
+        let ptr_data = segment.as_ptr() as *mut Directory;
+        let ptr_lock = unsafe { std::ptr::addr_of_mut!((*ptr_data).lock) };
+        let shared_futex = SharedFutex::new(ptr_lock as *mut libc::c_void);
+
+        let mut coordinator = Coordinator {
+            mem_path: mem_path.to_string(),
+            options: options.clone(),
+            directory: ptr_data,
+            segment: Rc::new(segment),
+            mutex: shared_futex,
+            event_handles: HashMap::new(),
+            attached: false,
+        };
+
+        // The header is only published once a live process is attached, so a
+        // group being created never looks stale
+        coordinator.mutex.lock();
+        let ret = unsafe {
+            let directory = &mut *coordinator.directory;
+            let ret = prepare(directory);
+            if ret.is_ok() {
+                directory.attach_pid(std::process::id());
+                directory.magic = DIRECTORY_MAGIC;
+                directory.version = DIRECTORY_VERSION;
+            }
+            ret
+        };
+        coordinator.mutex.unlock(1);
+        ret?;
+        coordinator.attached = true;
+        Ok(coordinator)
+    }
+
+    // Creates the group, or resets it if it exists but no process is attached
+    pub fn create(mem_path: &str, options: &GroupOptions) -> Result<Self, String> {
+        if mem_path.len() >= MAX_GROUP_NAME_SIZE {
+            return Err(String::from("Group name too long"));
+        }
+        let segment = Segment::open(options, mem_path, std::mem::size_of::<Directory>(), true)?;
+
+        let directory = segment.as_ptr() as *mut Directory;
+        unsafe {
+            (*directory).check_access(&segment, libc::geteuid())?;
+        }
+
+        Coordinator::attach(mem_path, options, segment, |directory| {
+            // Recreating a group wipes its token, so it takes the token as well
+            if directory.is_valid() {
+                directory.check_join_token(options.get_join_token_hash())?;
+            }
+            // Other processes use the futex words, join the group as is
+            if !directory.is_valid() || !directory.has_live_attachments() {
+                directory.reset(mem_path, options);
+            }
+            Ok(())
+        })
+    }
+
+    // Joins a group, failing if it does not exist
+    pub fn open(mem_path: &str, options: &GroupOptions) -> Result<Self, String> {
+        let segment = Segment::open(options, mem_path, std::mem::size_of::<Directory>(), false)?;
+
+        let directory = segment.as_ptr() as *mut Directory;
+        unsafe {
+            (*directory).check_access(&segment, libc::geteuid())?;
+        }
+
+        Coordinator::attach(mem_path, options, segment, |_| Ok(()))
+    }
+
+    // Creates a group on a memfd. It has no name on the host and can only be
+    // joined by processes the descriptor is handed over to.
+    pub fn create_anonymous(mem_path: &str) -> Result<Self, String> {
+        if mem_path.len() >= MAX_GROUP_NAME_SIZE {
+            return Err(String::from("Group name too long"));
+        }
+        let segment = Segment::anonymous(mem_path, std::mem::size_of::<Directory>())?;
+
+        let options = GroupOptions::new();
+        Coordinator::attach(mem_path, &options, segment, |directory| {
+            directory.reset(mem_path, &options);
+            Ok(())
+        })
+    }
+
+    // Joins an anonymous group from an inherited or received descriptor
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, String> {
+        let segment = Segment::from_fd(fd, std::mem::size_of::<Directory>())?;
+        let directory = segment.as_ptr() as *mut Directory;
+        if unsafe { !(*directory).is_valid() } {
+            return Err(String::from("Descriptor is not a group segment"));
+        }
+        unsafe {
+            (*directory).check_access(&segment, libc::geteuid())?;
+        }
+        let mem_path = unsafe { (*directory).get_name() };
+
+        Coordinator::attach(&mem_path, &GroupOptions::new(), segment, |_| Ok(()))
+    }
+
+    // Descriptor of an anonymous group, None for named groups
+    pub fn get_fd(&self) -> Option<RawFd> {
+        self.segment.get_fd()
+    }
+
+    // Copy of the descriptor that is kept open across exec, to be passed to
+    // a child process which then calls `Coordinator::from_fd`
+    pub fn inheritable_fd(&self) -> Result<OwnedFd, String> {
+        let fd = self.get_fd().ok_or("Group is not anonymous")?;
+        segment::inheritable_fd(fd)
+    }
+
+    pub fn send_fd(&self, socket: &UnixStream) -> Result<(), String> {
+        let fd = self.get_fd().ok_or("Group is not anonymous")?;
+        segment::send_fd(socket, fd)
+    }
+
+    // For same uid only groups the sender must run as the group owner too
+    pub fn receive_fd(socket: &UnixStream) -> Result<Self, String> {
+        let peer_uid = segment::peer_uid(socket)?;
+        let coordinator = Coordinator::from_fd(segment::recv_fd(socket)?)?;
+        unsafe {
+            (*coordinator.directory).check_access(&coordinator.segment, peer_uid)?;
+        }
+        Ok(coordinator)
+    }
+
+    // Opens another mapping of the group, e.g. for a background thread
+    pub(crate) fn location(&self) -> Result<GroupLocation, String> {
+        match self.get_fd() {
+            Some(fd) => Ok(GroupLocation::Fd(segment::private_fd(fd)?)),
+            None => Ok(GroupLocation::Named(
+                self.mem_path.clone(),
+                self.options.clone(),
+            )),
+        }
+    }
+
+    fn unlink(&self) -> Result<(), String> {
+        // Anonymous groups go away with their last descriptor
+        if self.get_fd().is_some() {
+            return Ok(());
+        }
+        segment::unlink(self.options.get_root(), &self.mem_path)
+    }
+
+    // Creates or joins a group with the default options
+    pub fn new(mem_path: &str) -> Result<Self, String> {
+        Coordinator::create(mem_path, &GroupOptions::new())
+    }
+
+    pub fn new_clean(mem_path: &str) -> Result<Self, String> {
+        clean_shared_files(&GroupOptions::new().directory(), mem_path);
+
+        Coordinator::new(mem_path)
+    }
+
+    // Joins the group as is, creating it only if it does not exist yet
+    pub fn open_existing(mem_path: &str) -> Result<Self, String> {
+        let options = GroupOptions::new();
+        match Coordinator::open(mem_path, &options) {
+            Err(e) if e == ERROR_GROUP_NOT_FOUND => Coordinator::create(mem_path, &options),
+            ret => ret,
+        }
+    }
+
+    // The mapping itself is released once the coordinator and every handle
+    // obtained from it are dropped. The group is unlinked when requested or
+    // when this was the last coordinator attached to a non persistent group.
+    pub fn close(&mut self, unlink: bool) -> Result<(), String> {
+        // Send fake events to possibly unblock waiters
+        // Notify with internal event
+        let _ = self.notify_builtin(BUILTIN_EVENT_NEW_PARTICIPANT);
+        let _ = self.notify_builtin(BUILTIN_EVENT_NEW_EVENT);
+
+        self.detach(unlink)
+    }
+
+    // Unlinks the group when requested or when this was the last coordinator
+    // of a non persistent group. The unlink happens under the directory lock,
+    // so no process can attach in between.
+    fn detach(&mut self, unlink: bool) -> Result<(), String> {
+        if !self.attached {
+            return if unlink { self.unlink() } else { Ok(()) };
+        }
+        self.attached = false;
+
+        self.mutex.lock();
+        let last = unsafe {
+            let directory = &mut *self.directory;
+            directory.detach_pid(std::process::id());
+            directory.attach_count == 0 && directory.persistent == 0
+        };
+        let ret = if unlink {
+            self.unlink()
+        } else {
+            if last {
+                let _ = self.unlink();
             }
+            Ok(())
+        };
+        self.mutex.unlock(1);
+        ret
+    }
+
+    // Persistent groups outlive their processes and must be unlinked explicitly
+    pub fn set_persistent(&mut self, persistent: bool) {
+        self.mutex.lock();
+        unsafe {
+            (*self.directory).persistent = persistent as u32;
+        }
+        self.mutex.unlock(1);
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        unsafe { (*self.directory).persistent != 0 }
+    }
+
+    pub fn get_attach_count(&self) -> u32 {
+        unsafe { (*self.directory).attach_count }
+    }
+
+    pub fn get_number_of_participants(&self) -> u64 {
+        unsafe { (*self.directory).participant_count() }
+    }
+
+    pub fn get_number_of_events(&self) -> u64 {
+        unsafe { (*self.directory).event_count() }
+    }
+
+    pub fn get_path(&self) -> String {
+        self.mem_path.clone()
+    }
+
+    pub fn get_options(&self) -> &GroupOptions {
+        &self.options
+    }
+
+    // Token presented by add_participant, for groups not joined with GroupOptions
+    pub fn set_join_token(&mut self, token: &str) {
+        self.options.set_join_token(token);
+    }
+
+    pub(crate) fn builtin_waitable(&mut self, event_name: &str) -> Option<SharedFutex> {
+        let index = BUILTIN_EVENTS.iter().position(|e| *e == event_name)?;
+        let ptr_futex = unsafe { std::ptr::addr_of_mut!((*self.directory).builtin_events[index]) };
+        Some(SharedFutex::new(ptr_futex as *mut libc::c_void))
+    }
+
+    fn notify_builtin(&mut self, event_name: &str) -> Result<(), String> {
+        debug!("   |-> Notifying builtin event. {}", event_name);
+        let waitable = self.builtin_waitable(event_name);
+        if waitable.is_none() {
+            return Err(String::from("Unknown builtin event"));
+        }
+        let mut waitable = waitable.unwrap();
+        debug!(
+            "   |-> Notifying builtin event {}. Old value {}",
+            event_name,
+            waitable.get_futex_value()
+        );
+
+        waitable.set_futex_value(0);
+        waitable.post_with_value(1, WAKE_ALL);
+
+        Ok(())
+    }
+
+    pub fn add_participant(&mut self, name: &str) -> Result<u64, String> {
+        debug!("Creating new participant '{}'", name);
+        check_participant_name(name)?;
+        unsafe { (*self.directory).check_join_token(self.options.get_join_token_hash())? };
+        self.mutex.lock();
+
+        // Check if participant already exists
+        let max_id = unsafe { (*self.directory).participant_count() };
+        for i in 0..max_id {
+            let p = unsafe { (*self.directory).participants[i as usize] };
+            let p_name = p.get_name();
+            if p_name == name {
+                self.mutex.unlock(1);
+                log::error!("Participant already exists");
+                return Err(String::from("Participant already exists"));
+            }
+        }
+
+        let ret = self.insert_participant(name, 0);
+        self.mutex.unlock(1);
+
+        if let Ok(id) = ret {
+            self.record(JournalOp::ParticipantJoined, id, u64::MAX);
+            // Notify with internal event
+            debug!(" |-> Notifying new participant");
+            let _ = self.notify_builtin(BUILTIN_EVENT_NEW_PARTICIPANT);
+        }
+
+        ret
+    }
+
+    // Must be called with the directory lock held
+    fn insert_participant(&mut self, name: &str, token_hash: u64) -> Result<u64, String> {
+        let mut participant = Participant::new();
+
+        let max_id = unsafe { (*self.directory).participant_count() };
+        if max_id >= MAX_PARTICIPANTS as u64 {
+            log::error!("Max number of participants reached");
+            return Err(String::from("Max number of participants reached"));
+        }
+
+        unsafe {
+            participant.id = (*self.directory).last_participant_id;
+            let name_bytes = name.as_bytes();
+            participant.name[..name_bytes.len()].copy_from_slice(name_bytes);
+            participant.set_process_info();
+            participant.reclaim_token_hash = token_hash;
+            (*self.directory).participants[participant.id as usize] = participant;
+            (*self.directory).last_participant_id += 1;
+            debug!(
+                " |-> Participant created with id {}. Next id: {}",
+                participant.id,
+                (*self.directory).last_participant_id
+            );
+        }
+
+        Ok(participant.id)
+    }
+
+    // Takes over the slot of a participant after a restart. The slot is granted when
+    // its previous process is dead or when the reclaim token matches. Registers the
+    // participant with that token if it does not exist yet.
+    pub fn reclaim_participant(&mut self, name: &str, token: &str) -> Result<u64, String> {
+        debug!("Reclaiming participant '{}'", name);
+        check_participant_name(name)?;
+        unsafe { (*self.directory).check_join_token(self.options.get_join_token_hash())? };
+        let token_hash = if token.is_empty() {
+            0
+        } else {
+            fnv1a(token.as_bytes())
+        };
+
+        self.mutex.lock();
+        let max_id = unsafe { (*self.directory).participant_count() };
+        let mut ret = None;
+        for i in 0..max_id {
+            let participant = unsafe { &mut (*self.directory).participants[i as usize] };
+            if participant.get_name() != name {
+                continue;
+            }
+
+            let token_matches = token_hash != 0 && participant.reclaim_token_hash == token_hash;
+            if participant.is_alive() && !token_matches {
+                ret = Some(Err(String::from(
+                    "Participant is still alive and the reclaim token does not match",
+                )));
+                break;
+            }
+
+            debug!(" |-> Participant {} reclaimed", participant.id);
+            participant.set_process_info();
+            participant.reclaim_token_hash = token_hash;
+            participant.stale = 0;
+            participant.heartbeat_ns = monotonic_ns();
+            ret = Some(Ok(participant.id));
+            break;
+        }
+
+        let ret = match ret {
+            Some(ret) => ret,
+            None => self.insert_participant(name, token_hash),
+        };
+        self.mutex.unlock(1);
+
+        if let Ok(id) = ret {
+            self.record(JournalOp::ParticipantJoined, id, u64::MAX);
+            let _ = self.notify_builtin(BUILTIN_EVENT_NEW_PARTICIPANT);
+        }
+
+        ret
+    }
+
+    // Events are created on behalf of a registered participant, which they
+    // count against the quota of
+    pub fn add_event(&mut self, participant_id: u64, name: &str) -> Result<EventHandle, String> {
+        if participant_id >= self.get_number_of_participants() {
+            return Err(String::from("Participant not found"));
+        }
+        self.insert_event(participant_id, name)
+    }
+
+    // Also used for the crate's own events, owned by NO_PARTICIPANT
+    fn insert_event(&mut self, participant_id: u64, name: &str) -> Result<EventHandle, String> {
+        self.mutex.lock();
+
+        let max_id = unsafe { (*self.directory).event_count() };
+
+        // Prepend coordinator name to event name
+        let name = self.mem_path.to_string() + "_" + name;
+
+        let mut index = None;
+        // Check if event already exists
+        for i in 0..max_id {
+            let e = unsafe { (*self.directory).events[i as usize] };
+            let e_name = e.get_name();
+            if e_name == name {
+                index = Some(i);
+                break;
+            }
+        }
+
+        if index.is_none() {
+            if max_id >= MAX_EVENTS as u64 {
+                self.mutex.unlock(1);
+                return Err(String::from("Max number of events reached"));
+            }
+            let over_quota = unsafe {
+                let directory = &*self.directory;
+                directory
+                    .event_quota(participant_id)
+                    .is_some_and(|quota| directory.events_owned(participant_id) >= quota as u64)
+            };
+            if over_quota {
+                self.mutex.unlock(1);
+                return Err(String::from(ERROR_QUOTA_EXCEEDED));
+            }
+
+            debug!("|-> Creating new event '{}'", name);
+            let mut event = Event::new();
+            event.set_id(max_id);
+            event.set_owner(participant_id);
+            let ret = event.set_name(name.as_str());
+            if ret.is_err() {
+                self.mutex.unlock(1);
+                return Err(String::from("Error setting event name"));
+            }
+
+            unsafe {
+                (*self.directory).events[max_id as usize] = event;
+                (*self.directory).last_event_id += 1;
+            }
+            self.record(JournalOp::EventCreated, participant_id, max_id);
+            index = Some(max_id);
+        }
+
+        let handle = self.get_event_handle(index.unwrap(), participant_id);
+        self.mutex.unlock(1);
+        // Notify with internal event
+        let _ = self.notify_builtin(BUILTIN_EVENT_NEW_EVENT);
+
+        handle
+    }
+
+    // Handle on an existing event for processes that are not participants,
+    // only events anyone may trigger or wait on can be used with it
+    pub fn open_event(&mut self, name: &str) -> Result<EventHandle, String> {
+        let name = self.mem_path.to_string() + "_" + name;
+        self.mutex.lock();
+        let max_id = unsafe { (*self.directory).event_count() };
+        let mut handle = Err(String::from("Event not found"));
+        for i in 0..max_id {
+            let event = unsafe { (*self.directory).events[i as usize] };
+            if event.get_name() == name {
+                handle = self.get_event_handle(i, NO_PARTICIPANT);
+                break;
+            }
+        }
+        self.mutex.unlock(1);
+        handle
+    }
+
+    // Must be called with the directory lock held
+    fn get_event_handle(&mut self, id: u64, participant_id: u64) -> Result<EventHandle, String> {
+        if let Some(handle) = self.event_handles.get(&(id, participant_id)) {
+            return Ok(handle.clone());
+        }
+
+        let event = unsafe { &mut (*self.directory).events[id as usize] as *mut Event };
+        let caller = unsafe {
+            if participant_id < (*self.directory).participant_count() {
+                &(*self.directory).participants[participant_id as usize] as *const Participant
+            } else {
+                std::ptr::null()
+            }
+        };
+        let handle = EventHandle::new(self.segment.clone(), event, caller);
+        if handle.is_none() {
+            return Err(String::from("Error creating waitable"));
+        }
+        let handle = handle.unwrap();
+        self.event_handles
+            .insert((id, participant_id), handle.clone());
+        Ok(handle)
+    }
+
+    // Only the owner of an event can change who may trigger and wait on it
+    pub fn set_event_access(
+        &mut self,
+        participant_id: u64,
+        name: &str,
+        trigger: EventAccess,
+        wait: EventAccess,
+        allowed: &[&str],
+    ) -> Result<(), String> {
+        let name = self.mem_path.to_string() + "_" + name;
+        self.mutex.lock();
+        let ret = unsafe {
+            let directory = &mut *self.directory;
+            let is_caller = directory.is_caller(participant_id);
+            let max_id = directory.event_count() as usize;
+            match directory.events[..max_id]
+                .iter_mut()
+                .find(|e| e.get_name() == name)
+            {
+                None => Err(String::from("Event not found")),
+                Some(event) if !is_caller || event.get_owner() != participant_id => {
+                    Err(String::from("Only the event owner can change its access"))
+                }
+                Some(event) => event.set_access(trigger, wait, allowed),
+            }
+        };
+        self.mutex.unlock(1);
+        ret
+    }
+
+    fn get_or_create_lock(&mut self, name: &str, kind: LockKind) -> Result<*mut Lock, String> {
+        self.mutex.lock();
+
+        let max_id = unsafe { (*self.directory).last_lock_id };
+        for i in 0..max_id {
+            let l = unsafe { (*self.directory).locks[i as usize] };
+            if l.get_name() == name {
+                self.mutex.unlock(1);
+                if l.get_kind() != Some(kind) {
+                    return Err(String::from("Lock already exists with a different kind"));
+                }
+                return Ok(unsafe { &mut (*self.directory).locks[i as usize] as *mut Lock });
+            }
+        }
+
+        if max_id >= MAX_LOCKS as u64 {
+            self.mutex.unlock(1);
+            return Err(String::from("Max number of locks reached"));
+        }
+
+        debug!("|-> Creating new lock '{}'", name);
+        let lock = unsafe { &mut (*self.directory).locks[max_id as usize] as *mut Lock };
+        let ret = unsafe { (*lock).init(max_id, name, kind) };
+        if ret.is_err() {
+            self.mutex.unlock(1);
+            return Err(String::from("Error setting lock name"));
+        }
+        unsafe {
+            (*self.directory).last_lock_id += 1;
+        }
+        self.mutex.unlock(1);
+
+        Ok(lock)
+    }
+
+    pub fn mutex(&mut self, participant_id: u64, name: &str) -> Result<SharedMutex, String> {
+        let lock = self.get_or_create_lock(name, LockKind::Mutex)?;
+        Ok(SharedMutex::new(self.segment.clone(), lock, participant_id))
+    }
+
+    pub fn rwlock(&mut self, participant_id: u64, name: &str) -> Result<SharedRwLock, String> {
+        let lock = self.get_or_create_lock(name, LockKind::RwLock)?;
+        Ok(SharedRwLock::new(
+            self.segment.clone(),
+            lock,
+            participant_id,
+        ))
+    }
+
+    pub fn get_number_of_locks(&self) -> u64 {
+        unsafe { (*self.directory).last_lock_id }
+    }
+
+    pub fn get_lock(&self, id: u64) -> Option<Lock> {
+        if id >= self.get_number_of_locks() {
+            return None;
+        }
+
+        unsafe {
+            let lock = (*self.directory).locks[id as usize];
+            Some(lock)
+        }
+    }
+
+    pub fn condvar(&mut self, name: &str, mutex: &SharedMutex) -> Result<Condvar, String> {
+        self.mutex.lock();
+
+        let max_id = unsafe { (*self.directory).last_condvar_id };
+        for i in 0..max_id {
+            let c = unsafe { (*self.directory).condvars[i as usize] };
+            if c.get_name() == name {
+                self.mutex.unlock(1);
+                if c.get_mutex_id() != mutex.get_id() {
+                    return Err(String::from("Condvar already bound to a different mutex"));
+                }
+                let condition =
+                    unsafe { &mut (*self.directory).condvars[i as usize] as *mut Condition };
+                return Ok(Condvar::new(self.segment.clone(), condition));
+            }
+        }
+
+        if max_id >= MAX_CONDVARS as u64 {
+            self.mutex.unlock(1);
+            return Err(String::from("Max number of condvars reached"));
+        }
+
+        debug!("|-> Creating new condvar '{}'", name);
+        let condition =
+            unsafe { &mut (*self.directory).condvars[max_id as usize] as *mut Condition };
+        let ret = unsafe { (*condition).init(max_id, name, mutex.get_id()) };
+        if ret.is_err() {
+            self.mutex.unlock(1);
+            return Err(String::from("Error setting condvar name"));
+        }
+        unsafe {
+            (*self.directory).last_condvar_id += 1;
+        }
+        self.mutex.unlock(1);
+
+        Ok(Condvar::new(self.segment.clone(), condition))
+    }
+
+    pub fn get_number_of_condvars(&self) -> u64 {
+        unsafe { (*self.directory).last_condvar_id }
+    }
+
+    fn get_or_create_counter(
+        &mut self,
+        name: &str,
+        kind: CounterKind,
+    ) -> Result<*mut Counter, String> {
+        self.mutex.lock();
+
+        let max_id = unsafe { (*self.directory).last_counter_id };
+        for i in 0..max_id {
+            let c = unsafe { (*self.directory).counters[i as usize] };
+            if c.get_name() == name {
+                self.mutex.unlock(1);
+                if c.get_kind() != Some(kind) {
+                    return Err(String::from("Counter already exists with a different kind"));
+                }
+                return Ok(unsafe { &mut (*self.directory).counters[i as usize] as *mut Counter });
+            }
+        }
+
+        if max_id >= MAX_COUNTERS as u64 {
+            self.mutex.unlock(1);
+            return Err(String::from("Max number of counters reached"));
+        }
+
+        debug!("|-> Creating new counter '{}'", name);
+        let counter = unsafe { &mut (*self.directory).counters[max_id as usize] as *mut Counter };
+        let ret = unsafe { (*counter).init(max_id, name, kind) };
+        if ret.is_err() {
+            self.mutex.unlock(1);
+            return Err(String::from("Error setting counter name"));
+        }
+        unsafe {
+            (*self.directory).last_counter_id += 1;
+        }
+        self.mutex.unlock(1);
+
+        Ok(counter)
+    }
+
+    pub fn counter(&mut self, name: &str) -> Result<SharedCounter, String> {
+        let counter = self.get_or_create_counter(name, CounterKind::Counter)?;
+        Ok(SharedCounter::new(self.segment.clone(), counter))
+    }
+
+    pub fn gauge(&mut self, name: &str) -> Result<SharedGauge, String> {
+        let counter = self.get_or_create_counter(name, CounterKind::Gauge)?;
+        Ok(SharedGauge::new(self.segment.clone(), counter))
+    }
+
+    pub fn get_number_of_counters(&self) -> u64 {
+        unsafe { (*self.directory).last_counter_id }
+    }
+
+    pub fn get_counter(&self, id: u64) -> Option<Counter> {
+        if id >= self.get_number_of_counters() {
+            return None;
+        }
+
+        unsafe {
+            let counter = (*self.directory).counters[id as usize];
+            Some(counter)
+        }
+    }
+
+    // Wakes the waiters of an event only if somebody registered it
+    fn trigger_if_registered(&mut self, name: &str) -> Result<(), String> {
+        if self.find_event(name).is_none() {
+            return Ok(());
+        }
+        self.open_event(name)?.trigger(WAKE_ALL)
+    }
+
+    pub fn kv_set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.mutex.lock();
+
+        let mut slot = None;
+        for i in 0..MAX_KV_ENTRIES {
+            let entry = unsafe { (*self.directory).kv[i] };
+            if entry.is_empty() {
+                if slot.is_none() {
+                    slot = Some(i);
+                }
+            } else if entry.get_key() == key {
+                slot = Some(i);
+                break;
+            }
+        }
+
+        if slot.is_none() {
+            self.mutex.unlock(1);
+            return Err(String::from("Max number of keys reached"));
+        }
+
+        let ret = unsafe { (*self.directory).kv[slot.unwrap()].set(key, value) };
+        self.mutex.unlock(1);
+        if let Err(e) = ret {
+            return Err(String::from(e));
+        }
+
+        debug!("|-> Key '{}' set", key);
+        self.trigger_if_registered(&kv_event_name(key))
+    }
+
+    pub fn kv_get(&mut self, key: &str) -> Option<String> {
+        self.mutex.lock();
+        let mut value = None;
+        for i in 0..MAX_KV_ENTRIES {
+            let entry = unsafe { (*self.directory).kv[i] };
+            if !entry.is_empty() && entry.get_key() == key {
+                value = Some(entry.get_value());
+                break;
+            }
+        }
+        self.mutex.unlock(1);
+        value
+    }
+
+    pub fn kv_delete(&mut self, key: &str) -> Result<bool, String> {
+        self.mutex.lock();
+        let mut deleted = false;
+        for i in 0..MAX_KV_ENTRIES {
+            let entry = unsafe { (*self.directory).kv[i] };
+            if !entry.is_empty() && entry.get_key() == key {
+                unsafe {
+                    (*self.directory).kv[i].clear();
+                }
+                deleted = true;
+                break;
+            }
+        }
+        self.mutex.unlock(1);
+
+        if !deleted {
+            return Ok(false);
+        }
+        debug!("|-> Key '{}' deleted", key);
+        self.trigger_if_registered(&kv_event_name(key))?;
+        Ok(true)
+    }
+
+    // Registers the change event of a key and returns its waitable
+    pub fn kv_watch(&mut self, key: &str) -> Result<EventHandle, String> {
+        self.insert_event(NO_PARTICIPANT, &kv_event_name(key))
+    }
+
+    // Every process must open the channel with the same type
+    pub fn state_channel<T: StateType>(&mut self, name: &str) -> Result<StateChannel<T>, String> {
+        check_layout::<T>()?;
+        self.mutex.lock();
+
+        let max_id = unsafe { (*self.directory).last_state_channel_id };
+        for i in 0..max_id {
+            let c = unsafe { (*self.directory).state_channels[i as usize] };
+            if c.get_name() == name {
+                self.mutex.unlock(1);
+                if let Err(e) = c.check_type::<T>() {
+                    return Err(String::from(e));
+                }
+                let channel =
+                    unsafe { &mut (*self.directory).state_channels[i as usize] as *mut Channel };
+                return Ok(StateChannel::new(self.segment.clone(), channel));
+            }
+        }
+
+        if max_id >= MAX_STATE_CHANNELS as u64 {
+            self.mutex.unlock(1);
+            return Err(String::from("Max number of state channels reached"));
+        }
+
+        debug!("|-> Creating new state channel '{}'", name);
+        let channel =
+            unsafe { &mut (*self.directory).state_channels[max_id as usize] as *mut Channel };
+        let ret = unsafe { (*channel).init::<T>(max_id, name) };
+        if ret.is_err() {
+            self.mutex.unlock(1);
+            return Err(String::from("Error setting state channel name"));
+        }
+        unsafe {
+            (*self.directory).last_state_channel_id += 1;
         }
+        self.mutex.unlock(1);
+
+        Ok(StateChannel::new(self.segment.clone(), channel))
     }
-}
 
-pub struct Coordinator {
-    mem_path: String,
-    directory: *mut Directory,
-    shm: posixaccessor::POSIXShm<Directory>,
-    mutex: rufutex::rufutex::SharedFutex,
-}
+    pub fn get_number_of_state_channels(&self) -> u64 {
+        unsafe { (*self.directory).last_state_channel_id }
+    }
 
-impl Coordinator {
-    pub fn new(mem_path: &str) -> Self {
-        let mut tmp_shm = posixaccessor::POSIXShm::<Directory>::new(
-            mem_path.to_string(),
-            std::mem::size_of::<Directory>(),
-        );
-        let ret: Result<(), String>;
+    pub fn get_state_channel(&self, id: u64) -> Option<Channel> {
+        if id >= self.get_number_of_state_channels() {
+            return None;
+        }
 
         unsafe {
-            ret = tmp_shm.open();
+            let channel = (*self.directory).state_channels[id as usize];
+            Some(channel)
         }
+    }
 
-        if ret.is_err() {
-            error!("Error opening shared memory");
-            panic!("Error opening shared memory");
+    // Must be called with the directory lock held
+    fn get_or_create_role(&mut self, name: &str) -> Result<*mut Role, String> {
+        let max_id = unsafe { (*self.directory).last_role_id };
+        for i in 0..max_id {
+            let r = unsafe { (*self.directory).roles[i as usize] };
+            if r.get_name() == name {
+                return Ok(unsafe { &mut (*self.directory).roles[i as usize] as *mut Role });
+            }
         }
 
-        let ptr_data: *mut Directory = tmp_shm.get_as_mut();
-        if ptr_data.is_null() {
-            error!("Error getting pointer to shared memory");
-            panic!("Error getting pointer to shared memory");
+        if max_id >= MAX_ROLES as u64 {
+            return Err(String::from("Max number of roles reached"));
         }
 
+        debug!("|-> Creating new role '{}'", name);
+        let role = unsafe { &mut (*self.directory).roles[max_id as usize] as *mut Role };
+        let ret = unsafe { (*role).init(max_id, name) };
+        if ret.is_err() {
+            return Err(String::from("Error setting role name"));
+        }
         unsafe {
-            *ptr_data = Directory::new();
+            (*self.directory).last_role_id += 1;
         }
+        Ok(role)
+    }
 
-        let mutex_path = mem_path.to_string() + "_mutex";
+    // Must be called with the directory lock held
+    fn is_leader_alive(&self, role: *mut Role) -> bool {
+        let leader = unsafe { (*role).get_leader() };
+        match leader {
+            Some(id) if id < MAX_PARTICIPANTS as u64 => unsafe {
+                (*self.directory).participants[id as usize].is_alive()
+            },
+            _ => false,
+        }
+    }
 
-        let mut shm_mutex =
-            posixaccessor::POSIXShm::<i32>::new(mutex_path, std::mem::size_of::<i32>());
-        unsafe {
-            let ret = shm_mutex.open();
-            if ret.is_err() {
-                error!("Error opening shared memory for mutex");
-                panic!("Error opening shared memory for mutex");
+    fn notify_leader_changed(&mut self, role: *mut Role) {
+        term_futex(role).post(WAKE_ALL);
+        let _ = self.notify_builtin(BUILTIN_EVENT_LEADER_CHANGED);
+    }
+
+    pub fn campaign(&mut self, participant_id: u64, role: &str) -> Result<bool, String> {
+        self.mutex.lock();
+        let role = match self.get_or_create_role(role) {
+            Ok(role) => role,
+            Err(e) => {
+                self.mutex.unlock(1);
+                return Err(e);
             }
-        }
+        };
 
-        let ptr_shm = shm_mutex.get_cptr_mut();
-        let shared_futex = SharedFutex::new(ptr_shm);
+        let leader = unsafe { (*role).get_leader() };
+        if leader == Some(participant_id) {
+            self.mutex.unlock(1);
+            return Ok(true);
+        }
 
-        Coordinator {
-            mem_path: mem_path.to_string(),
-            directory: ptr_data,
-            shm: posixaccessor::POSIXShm::new(
-                mem_path.to_string(),
-                std::mem::size_of::<Directory>(),
-            ),
-            mutex: shared_futex,
+        if leader.is_some() && self.is_leader_alive(role) {
+            self.mutex.unlock(1);
+            return Ok(false);
         }
-    }
 
-    pub fn new_clean(mem_path: &str) -> Self {
-        clean_shared_files("/dev/shm", mem_path);
+        debug!("|-> Participant {} elected leader", participant_id);
+        unsafe {
+            (*role).set_leader(participant_id);
+        }
+        self.mutex.unlock(1);
+        self.notify_leader_changed(role);
 
-        Coordinator::new(mem_path)
+        Ok(true)
     }
 
-    pub fn open_existing(mem_path: &str) -> Self {
-        let mut tmp_shm = posixaccessor::POSIXShm::<Directory>::new(
-            mem_path.to_string(),
-            std::mem::size_of::<Directory>(),
-        );
-        let ret: Result<(), String>;
+    pub fn resign(&mut self, participant_id: u64, role: &str) -> Result<(), String> {
+        self.mutex.lock();
+        let role = match self.get_or_create_role(role) {
+            Ok(role) => role,
+            Err(e) => {
+                self.mutex.unlock(1);
+                return Err(e);
+            }
+        };
 
-        unsafe {
-            ret = tmp_shm.open();
+        if unsafe { (*role).get_leader() } != Some(participant_id) {
+            self.mutex.unlock(1);
+            return Err(String::from("Participant is not the leader"));
         }
 
-        if ret.is_err() {
-            return Coordinator::new(mem_path);
+        unsafe {
+            (*role).set_leader(NO_PARTICIPANT);
         }
+        self.mutex.unlock(1);
+        self.notify_leader_changed(role);
 
-        let ptr_data: *mut Directory = tmp_shm.get_as_mut();
+        Ok(())
+    }
 
-        let mutex_path = mem_path.to_string() + "_mutex";
-        let mut shm_mutex =
-            posixaccessor::POSIXShm::<i32>::new(mutex_path, std::mem::size_of::<i32>());
-        unsafe {
-            let ret = shm_mutex.open();
-            if ret.is_err() {
-                return Coordinator::new(mem_path);
+    // Returns the leader of a role, stepping down leaders whose process is gone
+    pub fn current_leader(&mut self, role: &str) -> Option<u64> {
+        self.mutex.lock();
+        let role = match self.get_or_create_role(role) {
+            Ok(role) => role,
+            Err(_) => {
+                self.mutex.unlock(1);
+                return None;
             }
+        };
+
+        let leader = unsafe { (*role).get_leader() };
+        if leader.is_none() || self.is_leader_alive(role) {
+            self.mutex.unlock(1);
+            return leader;
         }
 
-        let ptr_shm = shm_mutex.get_cptr_mut();
-        let shared_futex = SharedFutex::new(ptr_shm);
+        debug!("|-> Leader {:?} is dead. Stepping down", leader);
+        unsafe {
+            (*role).set_leader(NO_PARTICIPANT);
+        }
+        self.mutex.unlock(1);
+        self.notify_leader_changed(role);
 
-        Coordinator {
-            mem_path: mem_path.to_string(),
-            directory: ptr_data,
-            shm: tmp_shm,
-            mutex: shared_futex,
+        None
+    }
+
+    // Blocks until the leadership of a role changes and returns the new leader
+    pub fn wait_leader_change(
+        &mut self,
+        role: &str,
+        timeout: std::time::Duration,
+    ) -> Result<Option<u64>, String> {
+        self.mutex.lock();
+        let ret = self.get_or_create_role(role);
+        self.mutex.unlock(1);
+        let ptr_role = ret?;
+
+        // A timeout too large to represent means waiting forever
+        let deadline = std::time::Instant::now().checked_add(timeout);
+        let term = load_term(ptr_role);
+        let mut futex = term_futex(ptr_role);
+        loop {
+            // Also detects leaders that died without resigning
+            let leader = self.current_leader(role);
+            if load_term(ptr_role) != term {
+                return Ok(leader);
+            }
+
+            let mut wait = LEADER_LIVENESS_POLL;
+            if let Some(deadline) = deadline {
+                let now = std::time::Instant::now();
+                if now >= deadline {
+                    return Err(String::from("Timeout waiting for leader change"));
+                }
+                wait = wait.min(deadline - now);
+            }
+            let timeout_spec = libc::timespec {
+                tv_sec: wait.as_secs() as i64,
+                tv_nsec: wait.subsec_nanos() as i64,
+            };
+            futex.wait_with_timeout(term, timeout_spec);
         }
     }
 
-    pub fn close(&mut self, unlink: bool) -> Result<(), String> {
-        let ret: Result<(), String>;
+    pub fn get_number_of_roles(&self) -> u64 {
+        unsafe { (*self.directory).last_role_id }
+    }
 
-        // Send fake events to possibly unblock waiters
-        // Notify with internal event
-        let _ = self.notify_builtin(BUILTIN_EVENT_NEW_PARTICIPANT);
-        let _ = self.notify_builtin(BUILTIN_EVENT_NEW_EVENT);
+    pub fn get_role(&self, id: u64) -> Option<Role> {
+        if id >= self.get_number_of_roles() {
+            return None;
+        }
 
         unsafe {
-            ret = self.shm.close(unlink);
+            let role = (*self.directory).roles[id as usize];
+            Some(role)
         }
+    }
 
-        if ret.is_err() {
-            return Err(String::from("Error closing shared memory"));
+    // Labels are chosen by the participant itself, see `EventAccess::Listed`
+    pub fn set_participant_label(
+        &mut self,
+        participant_id: u64,
+        key: &str,
+        value: &str,
+    ) -> Result<(), String> {
+        if participant_id >= self.get_number_of_participants() {
+            return Err(String::from("Participant does not exist"));
         }
 
-        Ok(())
+        self.mutex.lock();
+        let ret = unsafe {
+            let directory = &mut *self.directory;
+            if directory.is_caller(participant_id) {
+                directory.participants[participant_id as usize].set_label(key, value)
+            } else {
+                Err(String::from(ERROR_PERMISSION_DENIED))
+            }
+        };
+        self.mutex.unlock(1);
+        ret
     }
 
-    pub fn get_number_of_participants(&self) -> u64 {
-        unsafe { (*self.directory).last_participant_id }
+    // Records the operations done in the group from now on
+    pub fn set_journal_enabled(&mut self, enabled: bool) {
+        Journal::set_enabled(segment_journal(&self.segment), enabled);
     }
 
-    pub fn get_number_of_events(&self) -> u64 {
-        unsafe { (*self.directory).last_event_id }
+    pub fn is_journal_enabled(&self) -> bool {
+        Journal::is_enabled(segment_journal(&self.segment))
     }
 
-    pub fn get_path(&self) -> String {
-        self.mem_path.clone()
+    // Records still in the journal, oldest first
+    pub fn journal(&self) -> Vec<JournalRecord> {
+        Journal::records(segment_journal(&self.segment))
     }
 
-    fn notify_builtin(&mut self, event_name: &str) -> Result<(), String> {
-        let event_name = self.mem_path.to_string() + "_" + event_name;
-        debug!("   |-> Notifying builtin event. {}", event_name);
-        let mut event = Event::new();
-
-        let ret = event.set_name(event_name.as_str());
-        if ret.is_err() {
-            return Err(String::from("Error setting event name"));
-        }
-        event.set_id(42);
-
-        let waitable = event.get_waitable();
-        if waitable.is_none() {
-            return Err(String::from("Error creating waitable"));
-        }
-        let mut waitable = waitable.unwrap();
-        debug!(
-            "   |-> Notifying builtin event {}. Old value {}",
-            event_name,
-            waitable.get_futex_value()
+    // `object_id` is u64::MAX for operations not done on an event or lock
+    pub(crate) fn record(&self, op: JournalOp, participant_id: u64, object_id: u64) {
+        Journal::append(
+            segment_journal(&self.segment),
+            op,
+            participant_id,
+            object_id,
         );
-
-        waitable.set_futex_value(0);
-        waitable.post_with_value(1, u32::max_value());
-
-        Ok(())
     }
 
-    pub fn add_participant(&mut self, name: &str) -> Result<u64, String> {
-        debug!("Creating new participant '{}'", name);
-        let mut participant = Participant::new();
+    // Limit applied to the participants without their own quota, None for no limit
+    pub fn set_default_event_quota(&mut self, quota: Option<u32>) {
         self.mutex.lock();
-
-        let max_id = unsafe { (*self.directory).last_participant_id };
-        if max_id >= MAX_PARTICIPANTS as u64 {
-            self.mutex.unlock(1);
-            log::error!("Max number of participants reached");
-            return Err(String::from("Max number of participants reached"));
+        unsafe {
+            (*self.directory).default_event_quota = quota.unwrap_or(0);
         }
+        self.mutex.unlock(1);
+    }
 
-        // Check if participant already exists
-        for i in 0..max_id {
-            let p = unsafe { (*self.directory).participants[i as usize] };
-            let p_name = p.get_name();
-            if p_name == name {
-                self.mutex.unlock(1);
-                log::error!("Participant already exists");
-                return Err(String::from("Participant already exists"));
-            }
+    // Quota of a participant, None to fall back to the group default
+    pub fn set_event_quota(
+        &mut self,
+        participant_id: u64,
+        quota: Option<u32>,
+    ) -> Result<(), String> {
+        if participant_id >= self.get_number_of_participants() {
+            return Err(String::from("Participant does not exist"));
         }
 
+        self.mutex.lock();
         unsafe {
-            participant.id = (*self.directory).last_participant_id;
-            let name_bytes = name.as_bytes();
-            for i in 0..name_bytes.len() {
-                participant.name[i] = name_bytes[i];
-            }
-            (*self.directory).participants[participant.id as usize] = participant;
-            (*self.directory).last_participant_id += 1;
-            debug!(
-                " |-> Participant created with id {}. Next id: {}",
-                participant.id,
-                (*self.directory).last_participant_id
-            );
+            let participant = &mut (*self.directory).participants[participant_id as usize];
+            participant.event_quota_set = quota.is_some() as u32;
+            participant.event_quota = quota.unwrap_or(0);
         }
         self.mutex.unlock(1);
+        Ok(())
+    }
 
-        // Notify with internal event
-        debug!(" |-> Notifying new participant");
-        let _ = self.notify_builtin(BUILTIN_EVENT_NEW_PARTICIPANT);
+    // Effective quota of a participant, None if unlimited
+    pub fn get_event_quota(&self, participant_id: u64) -> Option<u32> {
+        unsafe { (*self.directory).event_quota(participant_id) }
+    }
 
-        Ok(participant.id)
+    // Number of events owned by a participant
+    pub fn get_event_usage(&mut self, participant_id: u64) -> u64 {
+        self.mutex.lock();
+        let usage = unsafe { (*self.directory).events_owned(participant_id) };
+        self.mutex.unlock(1);
+        usage
     }
 
-    pub fn add_event(&mut self, participant_id: u64, name: &str) -> Result<SharedFutex, String> {
+    pub fn set_heartbeat(
+        &mut self,
+        participant_id: u64,
+        period: std::time::Duration,
+        threshold: std::time::Duration,
+    ) -> Result<(), String> {
+        if participant_id >= self.get_number_of_participants() {
+            return Err(String::from("Participant does not exist"));
+        }
+
         self.mutex.lock();
+        unsafe {
+            let participant = &mut (*self.directory).participants[participant_id as usize];
+            participant.heartbeat_period_ms = period.as_millis() as u64;
+            participant.stale_threshold_ms = threshold.as_millis() as u64;
+            participant.heartbeat_ns = monotonic_ns();
+            participant.stale = 0;
+        }
+        self.mutex.unlock(1);
+        Ok(())
+    }
 
-        let max_id = unsafe { (*self.directory).last_event_id };
+    pub fn heartbeat(&mut self, participant_id: u64) -> Result<(), String> {
+        if participant_id >= self.get_number_of_participants() {
+            return Err(String::from("Participant does not exist"));
+        }
 
-        if max_id >= MAX_EVENTS as u64 {
-            self.mutex.unlock(1);
-            return Err(String::from("Max number of events reached"));
+        unsafe {
+            let ptr_heartbeat = std::ptr::addr_of_mut!(
+                (*self.directory).participants[participant_id as usize].heartbeat_ns
+            );
+            std::sync::atomic::AtomicU64::from_ptr(ptr_heartbeat)
+                .store(monotonic_ns(), std::sync::atomic::Ordering::SeqCst);
         }
+        Ok(())
+    }
 
-        // Prepend coordinator name to event name
-        let name = self.mem_path.to_string() + "_" + name;
-        debug!("|-> Creating new event '{}'", name);
+    // Watchdog check. Returns the participants whose heartbeat is older than their
+    // threshold and fires the builtin stale event when one of them just went stale.
+    pub fn stale_participants(&mut self) -> Vec<u64> {
+        let mut stale = Vec::new();
+        let mut newly_stale = false;
 
-        let mut exists = false;
-        // Check if event already exists
+        self.mutex.lock();
+        let max_id = unsafe { (*self.directory).participant_count() };
         for i in 0..max_id {
-            let e = unsafe { (*self.directory).events[i as usize] };
-            let e_name = e.get_name();
-            if e_name == name {
-                exists = true;
-                break;
+            let participant = unsafe { &mut (*self.directory).participants[i as usize] };
+            let age = participant.get_heartbeat_age();
+            if age.is_none() {
+                continue;
+            }
+            if age.unwrap() > participant.get_stale_threshold() {
+                if participant.stale == 0 {
+                    debug!("|-> Participant {} is stale", participant.id);
+                    Journal::append(
+                        unsafe { std::ptr::addr_of_mut!((*self.directory).journal) },
+                        JournalOp::ParticipantStale,
+                        participant.id,
+                        u64::MAX,
+                    );
+                    newly_stale = true;
+                }
+                participant.stale = 1;
+                stale.push(participant.id);
+            } else {
+                participant.stale = 0;
             }
         }
+        self.mutex.unlock(1);
 
-        let mut event = Event::new();
+        if newly_stale {
+            let _ = self.notify_builtin(BUILTIN_EVENT_PARTICIPANT_STALE);
+        }
+        stale
+    }
 
+    #[cfg(test)]
+    pub(crate) fn set_participant_pid(&mut self, id: u64, pid: u32) {
         unsafe {
-            let new_id = (*self.directory).last_event_id;
-            event.set_id(new_id);
-            let ret = event.set_name(name.as_str());
-            if ret.is_err() {
-                self.mutex.unlock(1);
-                return Err(String::from("Error setting event name"));
-            }
+            (*self.directory).participants[id as usize].pid = pid;
+        }
+    }
 
-            if !exists {
-                (*self.directory).events[new_id as usize] = event;
-                (*self.directory).events_owners[new_id as usize] = participant_id as u64;
-                (*self.directory).last_event_id += 1;
-            }
+    #[cfg(test)]
+    pub(crate) fn set_owner_uid(&mut self, uid: u32) {
+        unsafe {
+            (*self.directory).owner_uid = uid;
         }
-        self.mutex.unlock(1);
-        let waitable = event.get_waitable();
-        if waitable.is_none() {
-            return Err(String::from("Error creating waitable"));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_attached_pid(&mut self, pid: u32) {
+        self.mutex.lock();
+        unsafe {
+            let directory = &mut *self.directory;
+            directory.detach_pid(std::process::id());
+            directory.attach_pid(pid);
         }
-        // Notify with internal event
-        let _ = self.notify_builtin(BUILTIN_EVENT_NEW_EVENT);
+        self.mutex.unlock(1);
+    }
+
+    pub fn participants(&mut self) -> std::vec::IntoIter<Participant> {
+        self.mutex.lock();
+        let participants = unsafe { (*self.directory).participants_snapshot() };
+        self.mutex.unlock(1);
+        participants.into_iter()
+    }
+
+    pub fn events(&mut self) -> std::vec::IntoIter<Event> {
+        self.mutex.lock();
+        let events = unsafe { (*self.directory).events_snapshot() };
+        self.mutex.unlock(1);
+        events.into_iter()
+    }
+
+    pub fn find_participant(&mut self, name: &str) -> Option<Participant> {
+        self.participants().find(|p| p.get_name() == name)
+    }
+
+    pub fn find_event(&mut self, name: &str) -> Option<Event> {
+        let name = self.mem_path.to_string() + "_" + name;
+        self.events().find(|e| e.get_name() == name)
+    }
 
-        Ok(waitable.unwrap())
+    pub fn events_owned_by(&mut self, participant_id: u64) -> std::vec::IntoIter<Event> {
+        let events: Vec<Event> = self
+            .events()
+            .filter(|e| e.get_owner() == participant_id)
+            .collect();
+        events.into_iter()
     }
 
     pub fn get_participant(&self, id: u64) -> Option<Participant> {
-        if id > MAX_PARTICIPANTS as u64 {
+        if id >= unsafe { (*self.directory).participant_count() } {
             return None;
         }
 
@@ -352,7 +1817,7 @@ impl Coordinator {
 
     pub fn get_last_event_id(&mut self) -> Option<u64> {
         self.mutex.lock();
-        let current_id = unsafe { (*self.directory).last_event_id };
+        let current_id = unsafe { (*self.directory).event_count() };
         if current_id == 0 {
             self.mutex.unlock(1);
             return None;
@@ -363,7 +1828,7 @@ impl Coordinator {
 
     pub fn get_last_participant_id(&mut self) -> Option<u64> {
         self.mutex.lock();
-        let current_id = unsafe { (*self.directory).last_participant_id };
+        let current_id = unsafe { (*self.directory).participant_count() };
         if current_id == 0 {
             self.mutex.unlock(1);
             return None;
@@ -373,24 +1838,25 @@ impl Coordinator {
     }
 
     pub fn get_participant_id_by_event_id(&self, event_id: u64) -> Option<u64> {
-        if event_id > MAX_EVENTS as u64 {
-            return None;
-        }
+        unsafe { (*self.directory).get_event(event_id).map(|e| e.get_owner()) }
+    }
+}
 
-        unsafe {
-            let participant_id = (*self.directory).events_owners[event_id as usize];
-            Some(participant_id)
-        }
+impl Drop for Coordinator {
+    fn drop(&mut self) {
+        let _ = self.detach(false);
     }
 }
 
 #[cfg(test)]
 use std::ffi::CString;
+#[cfg(test)]
+use std::os::fd::AsRawFd;
 
 #[test]
 fn test_shared_memory_write_read() {
-    let mut coordinator = Coordinator::new("test_shared_memory_write_read");
-    let mut coordinator2 = Coordinator::open_existing("test_shared_memory_write_read");
+    let mut coordinator = Coordinator::new("test_shared_memory_write_read").unwrap();
+    let mut coordinator2 = Coordinator::open_existing("test_shared_memory_write_read").unwrap();
 
     let ret = coordinator.add_participant("test_participant");
     assert!(ret.is_ok());
@@ -414,10 +1880,134 @@ fn test_shared_memory_write_read() {
     // Dont check for error, since the shared memory is already unlinked
 }
 
+#[test]
+fn test_participant_metadata() {
+    let mut coordinator = Coordinator::new("test_participant_metadata").unwrap();
+    let participant_id = coordinator.add_participant("test_ingest").unwrap();
+    coordinator
+        .set_participant_label(participant_id, "role", "ingest")
+        .unwrap();
+    coordinator
+        .set_participant_label(participant_id, "shard", "3")
+        .unwrap();
+    coordinator
+        .set_participant_label(participant_id, "shard", "4")
+        .unwrap();
+    assert!(coordinator
+        .set_participant_label(participant_id, "a=b", "c")
+        .is_err());
+
+    let p = coordinator.get_participant(participant_id).unwrap();
+    assert_eq!(p.get_id(), participant_id);
+    assert_eq!(p.get_pid(), std::process::id());
+    assert_eq!(p.get_uid(), unsafe { libc::getuid() });
+    assert!(!p.get_exe().is_empty());
+    assert!(p.get_join_time() <= std::time::SystemTime::now());
+    assert_eq!(
+        p.get_labels(),
+        vec![
+            (String::from("role"), String::from("ingest")),
+            (String::from("shard"), String::from("4"))
+        ]
+    );
+    assert_eq!(p.get_label("role"), Some(String::from("ingest")));
+    assert_eq!(p.get_label("missing"), None);
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_lookup_and_enumeration() {
+    let mut coordinator = Coordinator::new("test_lookup_and_enumeration").unwrap();
+    let first = coordinator.add_participant("test_first").unwrap();
+    let second = coordinator.add_participant("test_second").unwrap();
+    coordinator.add_event(first, "test_event1").unwrap();
+    coordinator.add_event(second, "test_event2").unwrap();
+    coordinator.add_event(first, "test_event3").unwrap();
+
+    let names: Vec<String> = coordinator.participants().map(|p| p.get_name()).collect();
+    assert_eq!(names, vec!["test_first", "test_second"]);
+    assert_eq!(coordinator.events().count(), 3);
+
+    assert_eq!(
+        coordinator
+            .find_participant("test_second")
+            .unwrap()
+            .get_id(),
+        second
+    );
+    assert!(coordinator.find_participant("test_missing").is_none());
+
+    let event = coordinator.find_event("test_event2").unwrap();
+    assert_eq!(event.get_id(), 1);
+    assert_eq!(event.get_owner(), second);
+    assert!(coordinator.find_event("test_missing").is_none());
+
+    let owned: Vec<u64> = coordinator
+        .events_owned_by(first)
+        .map(|e| e.get_id())
+        .collect();
+    assert_eq!(owned, vec![0, 2]);
+
+    assert!(coordinator.get_participant(second + 1).is_none());
+    assert!(coordinator
+        .get_participant(MAX_PARTICIPANTS as u64)
+        .is_none());
+    assert_eq!(coordinator.get_participant_id_by_event_id(1), Some(second));
+    assert!(coordinator.get_participant_id_by_event_id(3).is_none());
+    assert!(coordinator
+        .get_participant_id_by_event_id(MAX_EVENTS as u64)
+        .is_none());
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_reclaim_participant() {
+    let mut coordinator = Coordinator::new("test_reclaim_participant").unwrap();
+    let long_name = "x".repeat(MAX_PARTICIPANT_NAME_SIZE + 1);
+    assert_eq!(
+        coordinator.add_participant(&long_name).err(),
+        Some(String::from("Name too long"))
+    );
+    assert!(coordinator
+        .reclaim_participant(&long_name, "secret")
+        .is_err());
+    let participant_id = coordinator
+        .reclaim_participant("test_service", "secret")
+        .unwrap();
+    coordinator.add_event(participant_id, "test_owned").unwrap();
+    assert!(coordinator.add_participant("test_service").is_err());
+
+    assert!(coordinator
+        .reclaim_participant("test_service", "wrong")
+        .is_err());
+    assert_eq!(
+        coordinator.reclaim_participant("test_service", "secret"),
+        Ok(participant_id)
+    );
+
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+    coordinator.set_participant_pid(participant_id, dead_pid);
+
+    assert_eq!(
+        coordinator.reclaim_participant("test_service", "other"),
+        Ok(participant_id)
+    );
+    let p = coordinator.get_participant(participant_id).unwrap();
+    assert_eq!(p.get_pid(), std::process::id());
+    assert_eq!(coordinator.get_number_of_participants(), 1);
+    assert_eq!(coordinator.events_owned_by(participant_id).count(), 1);
+
+    let _ = coordinator.close(true);
+}
+
 #[test]
 fn test_events() {
-    let mut coordinator = Coordinator::new("test_events");
-    let mut coordinator2 = Coordinator::open_existing("test_events");
+    let mut coordinator = Coordinator::new("test_events").unwrap();
+    let mut coordinator2 = Coordinator::open_existing("test_events").unwrap();
     let participant_id = coordinator
         .add_participant("test_shared_memory_participant")
         .unwrap();
@@ -442,3 +2032,222 @@ fn test_events() {
 
 //pidfd = syscall(SYS_pidfd_open, shm_base->prod_pid, 0);
 //event_fd = syscall(SYS_pidfd_getfd, pidfd, shm_base->event_fd, 0);
+
+#[test]
+fn test_attach_count() {
+    let shm_file = Path::new("/dev/shm/test_attach_count");
+    let coordinator = Coordinator::new_clean("test_attach_count").unwrap();
+    let coordinator2 = Coordinator::open_existing("test_attach_count").unwrap();
+    assert_eq!(coordinator.get_attach_count(), 2);
+    drop(coordinator2);
+    assert_eq!(coordinator.get_attach_count(), 1);
+    drop(coordinator);
+    assert!(!shm_file.exists());
+
+    let mut coordinator = Coordinator::new("test_attach_count").unwrap();
+    coordinator.set_persistent(true);
+    let mut coordinator2 = Coordinator::open_existing("test_attach_count").unwrap();
+    assert!(coordinator2.is_persistent());
+    let _ = coordinator2.close(false);
+    let _ = coordinator2.close(false);
+    assert_eq!(coordinator.get_attach_count(), 1);
+    drop(coordinator2);
+    drop(coordinator);
+    assert!(shm_file.exists());
+
+    let coordinator = Coordinator::open_existing("test_attach_count").unwrap();
+    assert_eq!(coordinator.get_attach_count(), 1);
+    segment::unlink(None, "test_attach_count").unwrap();
+}
+
+#[test]
+fn test_attach_after_crash() {
+    let shm_file = Path::new("/dev/shm/test_attach_after_crash");
+    let coordinator = Coordinator::new("test_attach_after_crash").unwrap();
+
+    // The child dies while attached, leaving its pid and count behind
+    let pid = unsafe { libc::fork() };
+    if pid == 0 {
+        let child = Coordinator::open("test_attach_after_crash", &GroupOptions::new());
+        std::mem::forget(child);
+        unsafe { libc::_exit(0) };
+    }
+    unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+    assert_eq!(coordinator.get_attach_count(), 2);
+    drop(coordinator);
+    assert!(shm_file.exists());
+
+    let coordinator = Coordinator::new("test_attach_after_crash").unwrap();
+    assert_eq!(coordinator.get_attach_count(), 1);
+    drop(coordinator);
+    assert!(!shm_file.exists());
+}
+
+#[test]
+fn test_new_clean() {
+    let mut other = Coordinator::new("test_new_clean_other").unwrap();
+    let mut coordinator = Coordinator::new_clean("test_new_clean").unwrap();
+    assert!(Path::new("/dev/shm/test_new_clean_other").exists());
+    assert_eq!(other.get_attach_count(), 1);
+
+    let _ = coordinator.close(true);
+    let _ = other.close(true);
+}
+
+#[test]
+fn test_anonymous_group() {
+    let mut coordinator = Coordinator::create_anonymous("test_anonymous_group").unwrap();
+    coordinator.kv_set("mode", "sandbox").unwrap();
+    let participant_id = coordinator.add_participant("test_participant").unwrap();
+    let mut event = coordinator.add_event(participant_id, "test_event").unwrap();
+    assert!(coordinator.get_fd().is_some());
+    assert!(!Path::new("/dev/shm/test_anonymous_group").exists());
+
+    let (sender, receiver) = UnixStream::pair().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::receive_fd(&receiver).unwrap();
+        assert_eq!(coordinator.get_path(), "test_anonymous_group");
+        assert_eq!(coordinator.kv_get("mode"), Some(String::from("sandbox")));
+        let mut event = coordinator.add_event(participant_id, "test_event").unwrap();
+        assert_eq!(
+            coordinator.find_event("test_event").unwrap().get_id(),
+            event.id()
+        );
+        event.trigger(1).unwrap();
+    });
+    coordinator.send_fd(&sender).unwrap();
+    handle.join().unwrap();
+    event.wait().unwrap();
+
+    let coordinator2 = Coordinator::from_fd(coordinator.inheritable_fd().unwrap()).unwrap();
+    assert_eq!(coordinator2.get_attach_count(), 2);
+
+    // Only the descriptors handed out to children survive exec
+    let cloexec =
+        |fd: &OwnedFd| unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC;
+    assert_eq!(cloexec(&coordinator.inheritable_fd().unwrap()), 0);
+    match coordinator.location().unwrap() {
+        GroupLocation::Fd(fd) => assert_ne!(cloexec(&fd), 0),
+        GroupLocation::Named(..) => panic!("Anonymous group located by name"),
+    }
+    assert!(Coordinator::from_fd(std::fs::File::open("/dev/null").unwrap().into()).is_err());
+    assert!(coordinator.close(true).is_ok());
+}
+
+#[test]
+fn test_group_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut options = GroupOptions::new();
+    options.set_mode(0o640);
+    options.set_gid(unsafe { libc::getegid() });
+    options.set_same_uid_only(true);
+    let mut coordinator = Coordinator::create("test_group_permissions", &options).unwrap();
+    let metadata = fs::metadata("/dev/shm/test_group_permissions").unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+
+    assert!(Coordinator::open("test_group_permissions", &GroupOptions::new()).is_ok());
+    coordinator.set_owner_uid(unsafe { libc::geteuid() } + 1);
+    assert_eq!(
+        Coordinator::open("test_group_permissions", &GroupOptions::new()).err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+    assert_eq!(
+        Coordinator::create("test_group_permissions", &options).err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_event_quota() {
+    let mut coordinator = Coordinator::new("test_event_quota").unwrap();
+    let greedy = coordinator.add_participant("test_greedy").unwrap();
+    let trusted = coordinator.add_participant("test_trusted").unwrap();
+    coordinator.set_default_event_quota(Some(2));
+    coordinator.set_event_quota(trusted, Some(3)).unwrap();
+    assert_eq!(coordinator.get_event_quota(greedy), Some(2));
+    assert_eq!(coordinator.get_event_quota(trusted), Some(3));
+
+    coordinator.add_event(greedy, "test_event1").unwrap();
+    coordinator.add_event(greedy, "test_event2").unwrap();
+    assert_eq!(
+        coordinator.add_event(greedy, "test_event3").err(),
+        Some(String::from(ERROR_QUOTA_EXCEEDED))
+    );
+    // Joining an existing event does not count against the quota
+    assert!(coordinator.add_event(greedy, "test_event1").is_ok());
+    assert_eq!(coordinator.get_event_usage(greedy), 2);
+
+    coordinator.add_event(trusted, "test_event3").unwrap();
+    coordinator.add_event(trusted, "test_event4").unwrap();
+    coordinator.add_event(trusted, "test_event5").unwrap();
+    assert!(coordinator.add_event(trusted, "test_event6").is_err());
+    // Unregistered ids cannot create events around the quotas
+    assert_eq!(
+        coordinator.add_event(NO_PARTICIPANT, "test_event6").err(),
+        Some(String::from("Participant not found"))
+    );
+    assert!(coordinator.add_event(trusted + 1, "test_event6").is_err());
+    assert!(coordinator.open_event("test_event6").is_err());
+    assert!(coordinator.open_event("test_event1").is_ok());
+
+    coordinator.set_event_quota(greedy, None).unwrap();
+    coordinator.set_default_event_quota(None);
+    assert_eq!(coordinator.get_event_quota(greedy), None);
+    assert!(coordinator.add_event(greedy, "test_event6").is_ok());
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_join_token() {
+    let mut options = GroupOptions::new();
+    options.set_join_token("secret");
+    let mut coordinator = Coordinator::create("test_join_token", &options).unwrap();
+    assert!(coordinator.add_participant("test_trusted").is_ok());
+
+    let mut stray = Coordinator::open("test_join_token", &GroupOptions::new()).unwrap();
+    assert_eq!(
+        stray.add_participant("test_stray").err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+    assert!(stray.reclaim_participant("test_stray", "").is_err());
+    stray.set_join_token("wrong");
+    assert!(stray.add_participant("test_stray").is_err());
+    stray.set_join_token("secret");
+    assert!(stray.add_participant("test_stray").is_ok());
+
+    drop(stray);
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_join_token_survives_new() {
+    let mut options = GroupOptions::new();
+    options.set_join_token("secret");
+    let mut coordinator = Coordinator::create("test_join_token_new", &options).unwrap();
+    coordinator.add_participant("test_trusted").unwrap();
+
+    // A process without the token cannot recreate the group to drop the token
+    assert_eq!(
+        Coordinator::new("test_join_token_new").err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+    assert_eq!(
+        crate::participant::Participant::new("test_stray", "test_join_token_new").err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+    assert_eq!(
+        Coordinator::create("test_join_token_new", &GroupOptions::new()).err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+
+    let mut stray = Coordinator::open("test_join_token_new", &GroupOptions::new()).unwrap();
+    assert!(stray.add_participant("test_stray").is_err());
+    assert!(stray.find_participant("test_trusted").is_some());
+
+    drop(stray);
+    let _ = coordinator.close(true);
+}
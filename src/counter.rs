@@ -0,0 +1,325 @@
+use crate::segment::Segment;
+use crate::MAX_COUNTER_NAME_SIZE;
+use crate::WAKE_ALL;
+
+use rufutex::rufutex::SharedFutex;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CounterKind {
+    Counter,
+    Gauge,
+}
+
+impl CounterKind {
+    fn to_raw(self) -> u32 {
+        match self {
+            CounterKind::Counter => 1,
+            CounterKind::Gauge => 2,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(CounterKind::Counter),
+            2 => Some(CounterKind::Gauge),
+            _ => None,
+        }
+    }
+}
+
+// C representation
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Counter {
+    id: u64,
+    value: u64,
+    kind: u32,
+    // Bumped on every update, the futex word waiters sleep on
+    sequence: u32,
+    name: [u8; MAX_COUNTER_NAME_SIZE],
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Counter {
+            id: 0,
+            value: 0,
+            kind: 0,
+            sequence: 0,
+            name: [0; MAX_COUNTER_NAME_SIZE],
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8(vname).unwrap()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_kind(&self) -> Option<CounterKind> {
+        CounterKind::from_raw(self.kind)
+    }
+
+    // Raw value; gauges store their i64 bit pattern
+    pub fn get_value(&self) -> u64 {
+        self.value
+    }
+
+    pub(crate) fn init(
+        &mut self,
+        id: u64,
+        name: &str,
+        kind: CounterKind,
+    ) -> Result<(), &'static str> {
+        if name.len() >= MAX_COUNTER_NAME_SIZE {
+            return Err("Name too long");
+        }
+        *self = Counter::new();
+        self.id = id;
+        self.kind = kind.to_raw();
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+}
+
+// Shared 64-bit value. Waiters sleep on a separate sequence word since no
+// 32-bit part of the value is guaranteed to change on every update.
+struct SharedValue {
+    // Keeps the mapping holding the counter alive
+    _segment: Rc<Segment>,
+    counter: *mut Counter,
+    futex: SharedFutex,
+}
+
+impl SharedValue {
+    fn new(segment: Rc<Segment>, counter: *mut Counter) -> Self {
+        let ptr_sequence = unsafe { std::ptr::addr_of_mut!((*counter).sequence) };
+        SharedValue {
+            _segment: segment,
+            counter,
+            futex: SharedFutex::new(ptr_sequence as *mut libc::c_void),
+        }
+    }
+
+    fn sequence(&self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(std::ptr::addr_of_mut!((*self.counter).sequence)) }
+    }
+
+    fn notify(&mut self) {
+        self.sequence().fetch_add(1, Ordering::SeqCst);
+        self.futex.post(WAKE_ALL);
+    }
+
+    fn atom(&self) -> &AtomicU64 {
+        unsafe { AtomicU64::from_ptr(std::ptr::addr_of_mut!((*self.counter).value)) }
+    }
+
+    fn get_name(&self) -> String {
+        unsafe { (*self.counter).get_name() }
+    }
+
+    fn get_id(&self) -> u64 {
+        unsafe { (*self.counter).get_id() }
+    }
+
+    fn load(&self) -> u64 {
+        self.atom().load(Ordering::SeqCst)
+    }
+
+    fn store(&mut self, value: u64) {
+        self.atom().store(value, Ordering::SeqCst);
+        self.notify();
+    }
+
+    fn fetch_add(&mut self, value: u64) -> u64 {
+        let previous = self.atom().fetch_add(value, Ordering::SeqCst);
+        self.notify();
+        previous
+    }
+
+    fn compare_exchange(&mut self, current: u64, new: u64) -> Result<u64, u64> {
+        let ret = self
+            .atom()
+            .compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst);
+        if ret.is_ok() {
+            self.notify();
+        }
+        ret
+    }
+
+    fn wait_until(&mut self, mut predicate: impl FnMut(u64) -> bool) -> u64 {
+        loop {
+            // Read before the value so an update in between is not missed
+            let sequence = self.sequence().load(Ordering::SeqCst);
+            let value = self.load();
+            if predicate(value) {
+                return value;
+            }
+            self.futex.wait(sequence);
+        }
+    }
+}
+
+pub struct SharedCounter {
+    value: SharedValue,
+}
+
+impl SharedCounter {
+    pub(crate) fn new(segment: Rc<Segment>, counter: *mut Counter) -> Self {
+        SharedCounter {
+            value: SharedValue::new(segment, counter),
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        self.value.get_name()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.value.get_id()
+    }
+
+    pub fn load(&self) -> u64 {
+        self.value.load()
+    }
+
+    pub fn store(&mut self, value: u64) {
+        self.value.store(value)
+    }
+
+    pub fn fetch_add(&mut self, value: u64) -> u64 {
+        self.value.fetch_add(value)
+    }
+
+    pub fn compare_exchange(&mut self, current: u64, new: u64) -> Result<u64, u64> {
+        self.value.compare_exchange(current, new)
+    }
+
+    pub fn wait_until(&mut self, predicate: impl FnMut(u64) -> bool) -> u64 {
+        self.value.wait_until(predicate)
+    }
+
+    pub fn wait_for_change(&mut self, old: u64) -> u64 {
+        self.value.wait_until(|value| value != old)
+    }
+}
+
+pub struct SharedGauge {
+    value: SharedValue,
+}
+
+impl SharedGauge {
+    pub(crate) fn new(segment: Rc<Segment>, counter: *mut Counter) -> Self {
+        SharedGauge {
+            value: SharedValue::new(segment, counter),
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        self.value.get_name()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.value.get_id()
+    }
+
+    pub fn load(&self) -> i64 {
+        self.value.load() as i64
+    }
+
+    pub fn store(&mut self, value: i64) {
+        self.value.store(value as u64)
+    }
+
+    pub fn fetch_add(&mut self, value: i64) -> i64 {
+        self.value.fetch_add(value as u64) as i64
+    }
+
+    pub fn compare_exchange(&mut self, current: i64, new: i64) -> Result<i64, i64> {
+        self.value
+            .compare_exchange(current as u64, new as u64)
+            .map(|v| v as i64)
+            .map_err(|v| v as i64)
+    }
+
+    pub fn wait_until(&mut self, mut predicate: impl FnMut(i64) -> bool) -> i64 {
+        self.value.wait_until(|value| predicate(value as i64)) as i64
+    }
+
+    pub fn wait_for_change(&mut self, old: i64) -> i64 {
+        self.wait_until(|value| value != old)
+    }
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_shared_counter() {
+    let mut coordinator = Coordinator::new("test_shared_counter").unwrap();
+    let mut counter = coordinator.counter("test_counter").unwrap();
+    assert_eq!(counter.load(), 0);
+    assert_eq!(counter.fetch_add(5), 0);
+    assert_eq!(counter.compare_exchange(5, 7), Ok(5));
+    assert_eq!(counter.compare_exchange(5, 9), Err(7));
+    assert_eq!(coordinator.get_number_of_counters(), 1);
+    assert_eq!(coordinator.get_counter(0).unwrap().get_value(), 7);
+    assert!(coordinator.gauge("test_counter").is_err());
+
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_shared_counter").unwrap();
+        let mut counter = coordinator.counter("test_counter").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        counter.fetch_add(1);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        counter.store(100);
+    });
+
+    assert_eq!(counter.wait_for_change(7), 8);
+    assert_eq!(counter.wait_until(|value| value >= 100), 100);
+    handle.join().unwrap();
+
+    // Leaves the low 32 bits of the value unchanged
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_shared_counter").unwrap();
+        let mut counter = coordinator.counter("test_counter").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        counter.fetch_add(1 << 32);
+    });
+
+    assert_eq!(counter.wait_for_change(100), 100 + (1 << 32));
+    handle.join().unwrap();
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_shared_gauge() {
+    let mut coordinator = Coordinator::new("test_shared_gauge").unwrap();
+    let mut gauge = coordinator.gauge("test_gauge").unwrap();
+    gauge.store(-3);
+    assert_eq!(gauge.fetch_add(-2), -3);
+    assert_eq!(gauge.load(), -5);
+    assert_eq!(gauge.compare_exchange(-5, 4), Ok(-5));
+    assert_eq!(gauge.wait_for_change(0), 4);
+
+    let gauge2 = coordinator.gauge("test_gauge").unwrap();
+    assert_eq!(gauge2.load(), 4);
+    assert_eq!(
+        coordinator.get_counter(0).unwrap().get_kind(),
+        Some(CounterKind::Gauge)
+    );
+
+    let _ = coordinator.close(true);
+}
@@ -0,0 +1,134 @@
+use crate::MAX_ROLE_NAME_SIZE;
+use crate::NO_PARTICIPANT;
+
+use rufutex::rufutex::SharedFutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// How often waiters re-check the liveness of the current leader
+pub(crate) const LEADER_LIVENESS_POLL: std::time::Duration = std::time::Duration::from_millis(100);
+
+// C representation
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Role {
+    id: u64,
+    leader: u64,
+    term: u32,
+    name: [u8; MAX_ROLE_NAME_SIZE],
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Role {
+    pub fn new() -> Self {
+        Role {
+            id: 0,
+            leader: NO_PARTICIPANT,
+            term: 0,
+            name: [0; MAX_ROLE_NAME_SIZE],
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8(vname).unwrap()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_leader(&self) -> Option<u64> {
+        if self.leader == NO_PARTICIPANT {
+            return None;
+        }
+        Some(self.leader)
+    }
+
+    // Incremented every time the leadership of the role changes
+    pub fn get_term(&self) -> u32 {
+        self.term
+    }
+
+    pub(crate) fn init(&mut self, id: u64, name: &str) -> Result<(), &'static str> {
+        if name.len() >= MAX_ROLE_NAME_SIZE {
+            return Err("Name too long");
+        }
+        *self = Role::new();
+        self.id = id;
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    // Must be called with the directory lock held. Wake up the waiters once released.
+    pub(crate) fn set_leader(&mut self, leader: u64) {
+        self.leader = leader;
+        term_atom(self).fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn term_atom<'a>(role: *mut Role) -> &'a AtomicU32 {
+    unsafe { AtomicU32::from_ptr(std::ptr::addr_of_mut!((*role).term)) }
+}
+
+pub(crate) fn load_term(role: *mut Role) -> u32 {
+    term_atom(role).load(Ordering::SeqCst)
+}
+
+pub(crate) fn term_futex(role: *mut Role) -> SharedFutex {
+    let ptr_term = unsafe { std::ptr::addr_of_mut!((*role).term) };
+    SharedFutex::new(ptr_term as *mut libc::c_void)
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_leader_election() {
+    let mut coordinator = Coordinator::new("test_leader_election").unwrap();
+    let first = coordinator.add_participant("test_replica1").unwrap();
+    let second = coordinator.add_participant("test_replica2").unwrap();
+
+    assert_eq!(coordinator.current_leader("writer"), None);
+    assert_eq!(coordinator.campaign(first, "writer"), Ok(true));
+    assert_eq!(coordinator.campaign(second, "writer"), Ok(false));
+    assert_eq!(coordinator.current_leader("writer"), Some(first));
+    assert!(coordinator.resign(second, "writer").is_err());
+
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_leader_election").unwrap();
+        coordinator
+            .wait_leader_change("writer", std::time::Duration::from_secs(5))
+            .unwrap()
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    coordinator.resign(first, "writer").unwrap();
+    assert_eq!(handle.join().unwrap(), None);
+
+    assert_eq!(coordinator.campaign(second, "writer"), Ok(true));
+    assert_eq!(coordinator.get_role(0).unwrap().get_term(), 3);
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_leader_election_dead_leader() {
+    let mut coordinator = Coordinator::new("test_leader_election_dead_leader").unwrap();
+    let first = coordinator.add_participant("test_replica1").unwrap();
+    let second = coordinator.add_participant("test_replica2").unwrap();
+    assert_eq!(coordinator.campaign(first, "writer"), Ok(true));
+
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+    coordinator.set_participant_pid(first, dead_pid);
+
+    assert_eq!(coordinator.current_leader("writer"), None);
+    assert_eq!(coordinator.campaign(second, "writer"), Ok(true));
+
+    let _ = coordinator.close(true);
+}
@@ -1,30 +1,84 @@
+use crate::coordinator::{segment_group_name, segment_journal, Participant as ParticipantRecord};
+use crate::journal::{Journal, JournalOp};
+use crate::segment::Segment;
 use crate::MAX_EVENT_NAME_SIZE;
+use crate::NO_PARTICIPANT;
+use crate::WAKE_ALL;
+use crate::{ERROR_PERMISSION_DENIED, MAX_EVENT_ACL_ENTRIES, MAX_LABEL_SIZE};
 
 use rufutex::rufutex::SharedFutex;
-use rushm::posixaccessor::POSIXShm;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
 
+#[cfg(test)]
 use log::debug;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventAccess {
+    Anyone,
+    OwnerOnly,
+    // The owner and the participants whose name or `key=value` label is listed.
+    // Participants set their own labels, so label entries are advisory only
+    // and do not stop a participant from claiming a listed label.
+    Listed,
+}
+
+impl EventAccess {
+    fn to_raw(self) -> u32 {
+        match self {
+            EventAccess::Anyone => 0,
+            EventAccess::OwnerOnly => 1,
+            EventAccess::Listed => 2,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            1 => EventAccess::OwnerOnly,
+            2 => EventAccess::Listed,
+            _ => EventAccess::Anyone,
+        }
+    }
+}
+
 // C representation
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct Event {
     id: u64,
+    owner: u64,
+    futex: u32,
+    // Bumped on every trigger, observers sleep on it since they cannot reset the futex
+    generation: u32,
+    trigger_access: u32,
+    wait_access: u32,
     name: [u8; MAX_EVENT_NAME_SIZE],
+    allowed: [[u8; MAX_LABEL_SIZE]; MAX_EVENT_ACL_ENTRIES],
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Event {
     pub fn new() -> Self {
         Event {
             id: 0,
+            owner: 0,
+            futex: 0,
+            generation: 0,
+            trigger_access: 0,
+            wait_access: 0,
             name: [0; MAX_EVENT_NAME_SIZE],
+            allowed: [[0; MAX_LABEL_SIZE]; MAX_EVENT_ACL_ENTRIES],
         }
     }
 
     pub fn get_name(&self) -> String {
         let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
-        let name = String::from_utf8(vname).unwrap();
-        name
+        String::from_utf8(vname).unwrap()
     }
 
     pub fn get_id(&self) -> u64 {
@@ -35,6 +89,14 @@ impl Event {
         self.id = id;
     }
 
+    pub fn get_owner(&self) -> u64 {
+        self.owner
+    }
+
+    pub fn set_owner(&mut self, owner: u64) {
+        self.owner = owner;
+    }
+
     pub fn set_name(&mut self, name: &str) -> Result<(), &'static str> {
         if name.len() > MAX_EVENT_NAME_SIZE {
             return Err("Name too long");
@@ -46,31 +108,227 @@ impl Event {
         Ok(())
     }
 
-    pub fn get_waitable(&self) -> Option<SharedFutex> {
-        let name = self.get_name();
-        if name.is_empty() {
+    // The futex word lives in the record itself, so the waitable is only shared
+    // between processes when the event is stored in the directory segment.
+    pub(crate) fn get_waitable(&mut self) -> Option<SharedFutex> {
+        if self.name[0] == 0 {
             return None;
         }
-        debug!("* Creating shared futex for {}", name);
-        let mut shm = POSIXShm::<i64>::new(name.to_string(), std::mem::size_of::<i64>());
-        unsafe {
-            let ret = shm.open();
-            if ret.is_err() {
-                panic!("Failed to open shared memory");
-            }
+        let ptr_futex = std::ptr::addr_of_mut!(self.futex);
+        Some(SharedFutex::new(ptr_futex as *mut libc::c_void))
+    }
+
+    pub fn get_futex_value(&self) -> u32 {
+        self.futex
+    }
+
+    pub fn get_generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub(crate) fn generation_atom<'a>(event: *const Event) -> &'a AtomicU32 {
+        unsafe { AtomicU32::from_ptr(std::ptr::addr_of!((*event).generation) as *mut u32) }
+    }
+
+    pub(crate) fn generation_futex(event: *const Event) -> SharedFutex {
+        let ptr_generation = unsafe { std::ptr::addr_of!((*event).generation) };
+        SharedFutex::new(ptr_generation as *mut libc::c_void)
+    }
+
+    pub fn get_trigger_access(&self) -> EventAccess {
+        EventAccess::from_raw(self.trigger_access)
+    }
+
+    pub fn get_wait_access(&self) -> EventAccess {
+        EventAccess::from_raw(self.wait_access)
+    }
+
+    // Participant names and labels allowed by the `Listed` policies
+    pub fn get_allowed(&self) -> Vec<String> {
+        self.allowed
+            .iter()
+            .map(|entry| entry.iter().take_while(|&&c| c != 0).cloned().collect())
+            .filter(|entry: &Vec<u8>| !entry.is_empty())
+            .map(|entry| String::from_utf8(entry).unwrap())
+            .collect()
+    }
+
+    pub(crate) fn set_access(
+        &mut self,
+        trigger: EventAccess,
+        wait: EventAccess,
+        allowed: &[&str],
+    ) -> Result<(), String> {
+        if allowed.len() > MAX_EVENT_ACL_ENTRIES {
+            return Err(String::from("Too many allowed participants"));
+        }
+        if allowed
+            .iter()
+            .any(|entry| entry.is_empty() || entry.len() >= MAX_LABEL_SIZE)
+        {
+            return Err(String::from("Invalid allowed participant"));
         }
-        let ptr_shm = shm.get_cptr_mut();
-        let shared_futex = SharedFutex::new(ptr_shm);
-        Some(shared_futex)
+
+        self.allowed = [[0; MAX_LABEL_SIZE]; MAX_EVENT_ACL_ENTRIES];
+        for (slot, entry) in self.allowed.iter_mut().zip(allowed.iter()) {
+            slot[..entry.len()].copy_from_slice(entry.as_bytes());
+        }
+        self.trigger_access = trigger.to_raw();
+        self.wait_access = wait.to_raw();
+        Ok(())
+    }
+
+    // Callers without a participant are only let in by `Anyone`
+    fn allows(&self, access: EventAccess, caller: Option<&ParticipantRecord>) -> bool {
+        if access == EventAccess::Anyone {
+            return true;
+        }
+        let caller = match caller {
+            Some(caller) => caller,
+            None => return false,
+        };
+        if caller.get_id() == self.owner {
+            return true;
+        }
+        if access == EventAccess::OwnerOnly {
+            return false;
+        }
+
+        let name = caller.get_name();
+        let labels: Vec<String> = caller
+            .get_labels()
+            .iter()
+            .map(|(key, value)| key.to_string() + "=" + value)
+            .collect();
+        self.get_allowed()
+            .iter()
+            .any(|entry| *entry == name || labels.contains(entry))
+    }
+}
+
+// Waitable of an event stored in a group segment. Handles are cheap to clone
+// and keep the segment mapped until the last one is dropped.
+//
+// The futex word is 1 while the event is signaled and 0 otherwise.
+pub struct EventHandle {
+    segment: Rc<Segment>,
+    event: *mut Event,
+    // Participant the handle was obtained for, checked against the event policy
+    caller: *const ParticipantRecord,
+    futex: SharedFutex,
+}
+
+impl EventHandle {
+    pub(crate) fn new(
+        segment: Rc<Segment>,
+        event: *mut Event,
+        caller: *const ParticipantRecord,
+    ) -> Option<Self> {
+        let futex = unsafe { (*event).get_waitable()? };
+        Some(EventHandle {
+            segment,
+            event,
+            caller,
+            futex,
+        })
+    }
+
+    // A participant of another process cannot be acted on behalf of
+    fn verified_caller(&self) -> Option<&ParticipantRecord> {
+        unsafe { self.caller.as_ref() }.filter(|c| c.get_pid() == std::process::id())
+    }
+
+    fn check_access(&self, access: EventAccess) -> Result<(), String> {
+        let allowed = unsafe { (*self.event).allows(access, self.verified_caller()) };
+        if !allowed {
+            return Err(String::from(ERROR_PERMISSION_DENIED));
+        }
+        Ok(())
+    }
+
+    // Name the event was added with, without the group prefix
+    pub fn name(&self) -> String {
+        let name = unsafe { (*self.event).get_name() };
+        let prefix = segment_group_name(&self.segment) + "_";
+        match name.strip_prefix(&prefix) {
+            Some(name) => name.to_string(),
+            None => name,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        unsafe { (*self.event).get_id() }
+    }
+
+    fn value(&self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(std::ptr::addr_of_mut!((*self.event).futex)) }
+    }
+
+    // Signals the event and wakes up to `waiters` processes
+    pub fn trigger(&mut self, waiters: u32) -> Result<(), String> {
+        self.check_access(unsafe { (*self.event).get_trigger_access() })?;
+        let caller = unsafe { self.caller.as_ref() }.map_or(NO_PARTICIPANT, |c| c.get_id());
+        Journal::append(
+            segment_journal(&self.segment),
+            JournalOp::EventTriggered,
+            caller,
+            self.id(),
+        );
+        Event::generation_atom(self.event).fetch_add(1, Ordering::SeqCst);
+        Event::generation_futex(self.event).post(WAKE_ALL);
+        self.futex.post_with_value(1, waiters.min(WAKE_ALL));
+        Ok(())
+    }
+
+    // Blocks until the event is signaled and consumes the signal
+    pub fn wait(&mut self) -> Result<(), String> {
+        self.check_access(unsafe { (*self.event).get_wait_access() })?;
+        self.futex.wait(0);
+        if !self.is_signaled() {
+            //It was spurious wake up
+            return Err(String::from("Error waiting on event"));
+        }
+        self.reset();
+        Ok(())
+    }
+
+    // Returns false if the timeout expired before the event was signaled
+    pub fn wait_timeout(&mut self, timeout: std::time::Duration) -> Result<bool, String> {
+        self.check_access(unsafe { (*self.event).get_wait_access() })?;
+        let timeout_spec = libc::timespec {
+            tv_sec: timeout.as_secs() as i64,
+            tv_nsec: timeout.subsec_nanos() as i64,
+        };
+        self.futex.wait_with_timeout(0, timeout_spec);
+        let signaled = self.is_signaled();
+        self.reset();
+        Ok(signaled)
+    }
+
+    pub fn is_signaled(&self) -> bool {
+        self.value().load(Ordering::SeqCst) != 0
+    }
+
+    pub fn reset(&mut self) {
+        self.value().store(0, Ordering::SeqCst);
+    }
+}
+
+impl Clone for EventHandle {
+    fn clone(&self) -> Self {
+        EventHandle::new(self.segment.clone(), self.event, self.caller).unwrap()
     }
 }
 
 #[cfg(test)]
+use crate::coordinator::Coordinator;
+
 #[test]
 fn test_event() {
     let mut event = Event::new();
     assert_eq!(event.get_id(), 0);
     assert_eq!(event.get_name(), "");
+    assert!(event.get_waitable().is_none());
 
     event.set_id(42);
     assert_eq!(event.get_id(), 42);
@@ -84,60 +342,171 @@ fn test_event() {
 
     let mut shared_futex = shared_futex.unwrap();
     shared_futex.wait(1);
-    shared_futex.post(0);
+    shared_futex.post_with_value(7, 0);
+    assert_eq!(event.get_futex_value(), 7);
 }
 
 #[test]
 fn test_events() {
-    let mut event = Event::new();
-    assert_eq!(event.get_id(), 0);
-    assert_eq!(event.get_name(), "");
+    let mut coordinator = Coordinator::new("test_event_futex_words").unwrap();
+    let participant_id = coordinator.add_participant("test_event_owner").unwrap();
+    let mut event = coordinator
+        .add_event(participant_id, "test_event2")
+        .unwrap();
+    assert!(!event.is_signaled());
+    assert!(!event
+        .wait_timeout(std::time::Duration::from_millis(10))
+        .unwrap());
 
-    event.set_id(42);
-    assert_eq!(event.get_id(), 42);
+    // spawn a thread to wait on the event
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_event_futex_words").unwrap();
+        let mut event2 = coordinator
+            .add_event(participant_id, "test_event2")
+            .unwrap();
+        debug!("Waiting on event");
+        while event2.wait().is_err() {}
+        debug!("Event received");
+    });
 
-    let ret = event.set_name("test_event2");
-    assert!(ret.is_ok());
-    assert_eq!(event.get_name(), "test_event2");
+    //Sleep for a while to let the thread start
+    std::thread::sleep(std::time::Duration::from_millis(300));
 
-    let shared_futex = event.get_waitable();
-    assert!(shared_futex.is_some());
+    debug!("Triggering event");
+    event.trigger(32).unwrap();
+    handle.join().unwrap();
+    assert!(!event.is_signaled());
+    assert_eq!(event.name(), "test_event2");
+    assert_eq!(
+        coordinator.find_event(&event.name()).unwrap().get_id(),
+        event.id()
+    );
 
-    let mut shared_futex = shared_futex.unwrap();
+    event.trigger(0).unwrap();
+    assert!(event.is_signaled());
+    event.reset();
+    assert!(!event.is_signaled());
 
-    // spawn a thread to wait on the futex
-    let handle = std::thread::spawn(move || {
-        let mut event = Event::new();
-        assert_eq!(event.get_id(), 0);
-        assert_eq!(event.get_name(), "");
+    let _ = coordinator.close(true);
+}
 
-        event.set_id(42);
-        assert_eq!(event.get_id(), 42);
+#[test]
+fn test_event_handle_outlives_coordinator() {
+    let mut coordinator = Coordinator::new("test_event_handle_outlives_coordinator").unwrap();
+    let participant_id = coordinator.add_participant("test_event_owner").unwrap();
+    let handle = coordinator
+        .add_event(participant_id, "test_event3")
+        .unwrap();
+    let mut cached = coordinator
+        .add_event(participant_id, "test_event3")
+        .unwrap();
+    assert_eq!(handle.id(), cached.id());
 
-        let ret = event.set_name("test_event2");
-        assert!(ret.is_ok());
-        assert_eq!(event.get_name(), "test_event2");
+    let _ = coordinator.close(true);
+    drop(coordinator);
 
-        let shared_futex2 = event.get_waitable();
-        assert!(shared_futex2.is_some());
+    cached.trigger(0).unwrap();
+    assert!(handle.is_signaled());
+    assert_eq!(handle.name(), "test_event3");
+}
 
-        let mut shared_futex2 = shared_futex2.unwrap();
-        debug!("Waiting on futex for 42");
-        shared_futex2.wait(42);
-        debug!("Posting on futex for 1");
-        shared_futex2.post_with_value(1, 32);
-    });
+#[test]
+fn test_event_access() {
+    let mut coordinator = Coordinator::new("test_event_access").unwrap();
+    let owner = coordinator.add_participant("test_owner").unwrap();
+    let reader = coordinator.add_participant("test_reader").unwrap();
+    let monitor = coordinator.add_participant("test_monitor").unwrap();
+    let intruder = coordinator.add_participant("test_intruder").unwrap();
+    coordinator
+        .set_participant_label(monitor, "role", "monitor")
+        .unwrap();
 
-    //Sleep for a while to let the thread start
-    std::thread::sleep(std::time::Duration::from_millis(300));
+    let mut alarm = coordinator.add_event(owner, "alarm").unwrap();
+    coordinator
+        .set_event_access(
+            owner,
+            "alarm",
+            EventAccess::Listed,
+            EventAccess::OwnerOnly,
+            &["test_reader", "role=monitor"],
+        )
+        .unwrap();
+    assert!(coordinator
+        .set_event_access(
+            intruder,
+            "alarm",
+            EventAccess::Anyone,
+            EventAccess::Anyone,
+            &[]
+        )
+        .is_err());
+    let event = coordinator.find_event("alarm").unwrap();
+    assert_eq!(event.get_trigger_access(), EventAccess::Listed);
+    assert_eq!(event.get_allowed(), vec!["test_reader", "role=monitor"]);
 
-    debug!("Posting on futex for 42");
-    shared_futex.post_with_value(42, 32);
-    debug!("Waiting on futex for 1");
-    shared_futex.wait(1);
-    debug!("Wakeup on futex from 1");
-    debug!("Posting on futex for 42");
-    shared_futex.post_with_value(42, 32);
+    assert!(alarm.trigger(0).is_ok());
+    assert!(coordinator
+        .add_event(reader, "alarm")
+        .unwrap()
+        .trigger(0)
+        .is_ok());
+    assert!(coordinator
+        .add_event(monitor, "alarm")
+        .unwrap()
+        .trigger(0)
+        .is_ok());
+    let mut denied = coordinator.add_event(intruder, "alarm").unwrap();
+    assert_eq!(
+        denied.trigger(0).err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+    assert!(coordinator
+        .add_event(reader, "alarm")
+        .unwrap()
+        .wait_timeout(std::time::Duration::from_millis(1))
+        .is_err());
+    assert_eq!(
+        alarm.wait_timeout(std::time::Duration::from_millis(1)),
+        Ok(true)
+    );
 
-    handle.join().unwrap();
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_event_access_other_process() {
+    let mut coordinator = Coordinator::new("test_event_access_other_process").unwrap();
+    let owner = coordinator.add_participant("test_owner").unwrap();
+    let mut alarm = coordinator.add_event(owner, "alarm").unwrap();
+    coordinator
+        .set_event_access(
+            owner,
+            "alarm",
+            EventAccess::OwnerOnly,
+            EventAccess::OwnerOnly,
+            &[],
+        )
+        .unwrap();
+
+    // The owner now belongs to another process
+    coordinator.set_participant_pid(owner, std::process::id() + 1);
+    assert_eq!(
+        alarm.trigger(0).err(),
+        Some(String::from(ERROR_PERMISSION_DENIED))
+    );
+    assert!(coordinator
+        .set_event_access(
+            owner,
+            "alarm",
+            EventAccess::Anyone,
+            EventAccess::Anyone,
+            &[]
+        )
+        .is_err());
+    assert_eq!(
+        coordinator.set_participant_label(owner, "role", "monitor"),
+        Err(String::from(ERROR_PERMISSION_DENIED))
+    );
+
+    let _ = coordinator.close(true);
 }
@@ -0,0 +1,271 @@
+use crate::coordinator::{read_group_info, remove_group_if_stale};
+use crate::hash::fnv1a;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// POSIX shared memory objects are files under this directory
+pub(crate) const SHM_ROOT: &str = "/dev/shm";
+
+// Where and how the segments of a group are created
+#[derive(Debug, Clone, Default)]
+pub struct GroupOptions {
+    root: Option<PathBuf>,
+    mode: Option<u32>,
+    gid: Option<u32>,
+    same_uid_only: bool,
+    join_token_hash: Option<u64>,
+    journal: bool,
+}
+
+impl GroupOptions {
+    pub fn new() -> Self {
+        GroupOptions {
+            root: None,
+            mode: None,
+            gid: None,
+            same_uid_only: false,
+            join_token_hash: None,
+            journal: false,
+        }
+    }
+
+    // Store the group as regular files under `root` instead of POSIX shared
+    // memory objects, e.g. a tmpfs or hugetlbfs volume shared between containers
+    pub fn set_root(&mut self, root: &str) {
+        self.root = Some(PathBuf::from(root));
+    }
+
+    // None for POSIX shared memory
+    pub fn get_root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    // File mode of the segment when the group is created, 0o600 by default
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = Some(mode);
+    }
+
+    pub fn get_mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    // Owning group of the segment when the group is created
+    pub fn set_gid(&mut self, gid: u32) {
+        self.gid = Some(gid);
+    }
+
+    pub fn get_gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    // Only processes running as the creator's user may join, whatever the mode
+    pub fn set_same_uid_only(&mut self, same_uid_only: bool) {
+        self.same_uid_only = same_uid_only;
+    }
+
+    pub fn is_same_uid_only(&self) -> bool {
+        self.same_uid_only
+    }
+
+    // Shared secret required by add_participant. The creator stores it in the
+    // group, joiners must present the same one. Only its hash is kept.
+    pub fn set_join_token(&mut self, token: &str) {
+        self.join_token_hash = Some(fnv1a(token.as_bytes()));
+    }
+
+    pub(crate) fn get_join_token_hash(&self) -> Option<u64> {
+        self.join_token_hash
+    }
+
+    // Record the operations done in the group in its journal from creation
+    pub fn set_journal(&mut self, journal: bool) {
+        self.journal = journal;
+    }
+
+    pub fn is_journal(&self) -> bool {
+        self.journal
+    }
+
+    // Directory holding the group segments
+    pub(crate) fn directory(&self) -> PathBuf {
+        self.root.clone().unwrap_or_else(|| PathBuf::from(SHM_ROOT))
+    }
+}
+
+// Snapshot of a group read from its directory header
+#[derive(Debug, Clone)]
+pub struct GroupInfo {
+    name: String,
+    attach_count: u32,
+    persistent: bool,
+    participants: u64,
+    events: u64,
+    live_pids: Vec<u32>,
+}
+
+impl GroupInfo {
+    pub(crate) fn new(
+        name: &str,
+        attach_count: u32,
+        persistent: bool,
+        participants: u64,
+        events: u64,
+        live_pids: Vec<u32>,
+    ) -> Self {
+        GroupInfo {
+            name: name.to_string(),
+            attach_count,
+            persistent,
+            participants,
+            events,
+            live_pids,
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn get_attach_count(&self) -> u32 {
+        self.attach_count
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    pub fn get_number_of_participants(&self) -> u64 {
+        self.participants
+    }
+
+    pub fn get_number_of_events(&self) -> u64 {
+        self.events
+    }
+
+    // Attached processes and participants still running
+    pub fn get_live_pids(&self) -> Vec<u32> {
+        self.live_pids.clone()
+    }
+
+    // Stale groups have no running process left and are not persistent
+    pub fn is_stale(&self) -> bool {
+        self.live_pids.is_empty() && !self.persistent
+    }
+}
+
+fn group_names(path: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            if let Ok(file_name) = entry.file_name().into_string() {
+                names.push(file_name);
+            }
+        }
+    }
+    names.sort();
+    names
+}
+
+// Every group on the host, other shared memory objects are skipped
+pub fn list_groups() -> Vec<GroupInfo> {
+    list_groups_in(&GroupOptions::new())
+}
+
+pub fn list_groups_in(options: &GroupOptions) -> Vec<GroupInfo> {
+    group_names(&options.directory())
+        .iter()
+        .filter_map(|name| read_group_info(options, name))
+        .collect()
+}
+
+pub fn find_group(name: &str) -> Option<GroupInfo> {
+    find_group_in(&GroupOptions::new(), name)
+}
+
+pub fn find_group_in(options: &GroupOptions, name: &str) -> Option<GroupInfo> {
+    read_group_info(options, name)
+}
+
+// Unlinks the stale groups and returns their names
+pub fn collect_stale_groups() -> Vec<String> {
+    collect_stale_groups_in(&GroupOptions::new())
+}
+
+pub fn collect_stale_groups_in(options: &GroupOptions) -> Vec<String> {
+    group_names(&options.directory())
+        .into_iter()
+        .filter(|name| remove_group_if_stale(options, name))
+        .collect()
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_group_discovery() {
+    // Stale groups are collected from a private root, never the real /dev/shm
+    let root = std::env::temp_dir().join("mpevent_test_group_discovery");
+    let _ = fs::remove_dir_all(&root);
+    let _ = fs::create_dir_all(&root);
+    let mut options = GroupOptions::new();
+    options.set_root(root.to_str().unwrap());
+
+    // A group whose only process died
+    let mut coordinator2 = Coordinator::create("test_group_discovery_dead", &options).unwrap();
+    let participant_id = coordinator2.add_participant("test_dead").unwrap();
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+    coordinator2.set_participant_pid(participant_id, dead_pid);
+    coordinator2.set_attached_pid(dead_pid);
+
+    let coordinator = Coordinator::create("test_group_discovery", &options).unwrap();
+    let info = find_group_in(&options, "test_group_discovery").unwrap();
+    assert_eq!(info.get_attach_count(), 1);
+    assert_eq!(info.get_live_pids(), vec![std::process::id()]);
+    assert!(!info.is_stale());
+    assert!(list_groups_in(&options)
+        .iter()
+        .any(|g| g.get_name() == "test_group_discovery"));
+    assert!(find_group("test_group_discovery").is_none());
+
+    let info = find_group_in(&options, "test_group_discovery_dead").unwrap();
+    assert_eq!(info.get_number_of_participants(), 1);
+    assert!(info.is_stale());
+
+    // Collecting a group must not touch groups sharing its name as prefix
+    let removed = collect_stale_groups_in(&options);
+    assert_eq!(removed, vec![String::from("test_group_discovery_dead")]);
+    assert!(find_group_in(&options, "test_group_discovery_dead").is_none());
+
+    drop(coordinator2);
+    drop(coordinator);
+    assert!(find_group_in(&options, "test_group_discovery").is_none());
+    let _ = fs::remove_dir(&root);
+}
+
+#[test]
+fn test_file_backed_group() {
+    let root = std::env::temp_dir().join("mpevent_test_file_backed_group");
+    let _ = fs::create_dir_all(&root);
+    let mut options = GroupOptions::new();
+    options.set_root(root.to_str().unwrap());
+
+    assert!(Coordinator::open("test_file_backed", &options).is_err());
+    let mut coordinator = Coordinator::create("test_file_backed", &options).unwrap();
+    coordinator.kv_set("volume", "shared").unwrap();
+    assert!(root.join("test_file_backed").exists());
+    assert!(find_group("test_file_backed").is_none());
+
+    let mut coordinator2 = Coordinator::open("test_file_backed", &options).unwrap();
+    assert_eq!(coordinator2.kv_get("volume"), Some(String::from("shared")));
+    let info = find_group_in(&options, "test_file_backed").unwrap();
+    assert_eq!(info.get_attach_count(), 2);
+    assert_eq!(list_groups_in(&options).len(), 1);
+
+    drop(coordinator2);
+    drop(coordinator);
+    assert!(!root.join("test_file_backed").exists());
+    let _ = fs::remove_dir(&root);
+}
@@ -0,0 +1,131 @@
+use crate::coordinator::Coordinator;
+use log::debug;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// CLOCK_MONOTONIC is system wide, so timestamps can be compared between processes
+pub(crate) fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// Background thread bumping the heartbeat of a participant until dropped
+pub struct Heartbeat {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    pub fn start(
+        coordinator: &Coordinator,
+        participant_id: u64,
+        period: Duration,
+    ) -> Result<Self, String> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let location = coordinator.location()?;
+
+        let handle = std::thread::spawn(move || {
+            let ret = location.open();
+            if ret.is_err() {
+                debug!(
+                    "Heartbeat cannot open group of participant {}",
+                    participant_id
+                );
+                return;
+            }
+            let mut coordinator = ret.unwrap();
+            debug!("Heartbeat started for participant {}", participant_id);
+            while !thread_stop.load(Ordering::SeqCst) {
+                if coordinator.heartbeat(participant_id).is_err() {
+                    break;
+                }
+                std::thread::park_timeout(period);
+            }
+            debug!("Heartbeat stopped for participant {}", participant_id);
+        });
+
+        Ok(Heartbeat {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+use crate::journal::JournalOp;
+
+#[test]
+fn test_stale_participants() {
+    let mut coordinator = Coordinator::new("test_stale_participants").unwrap();
+    let participant_id = coordinator.add_participant("test_hung").unwrap();
+    let other_id = coordinator.add_participant("test_no_heartbeat").unwrap();
+    coordinator
+        .set_heartbeat(
+            participant_id,
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+        )
+        .unwrap();
+    assert!(coordinator.stale_participants().is_empty());
+
+    coordinator.set_journal_enabled(true);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(coordinator.stale_participants(), vec![participant_id]);
+    let records = coordinator.journal();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].get_op(), Some(JournalOp::ParticipantStale));
+    assert_eq!(records[0].get_participant_id(), Some(participant_id));
+    assert!(coordinator
+        .get_participant(participant_id)
+        .unwrap()
+        .is_stale());
+    assert!(!coordinator.get_participant(other_id).unwrap().is_stale());
+
+    coordinator.heartbeat(participant_id).unwrap();
+    assert!(coordinator.stale_participants().is_empty());
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_heartbeat_thread() {
+    let mut participant =
+        crate::participant::Participant::new("test_alive", "test_heartbeat_thread").unwrap();
+    participant
+        .enable_heartbeat(Duration::from_millis(10), Duration::from_millis(100))
+        .unwrap();
+
+    let mut coordinator = Coordinator::open_existing("test_heartbeat_thread").unwrap();
+    std::thread::sleep(Duration::from_millis(250));
+    assert!(coordinator.stale_participants().is_empty());
+
+    participant.disable_heartbeat();
+    std::thread::sleep(Duration::from_millis(250));
+    assert_eq!(coordinator.stale_participants(), vec![participant.get_id()]);
+
+    let _ = participant.close();
+}
@@ -0,0 +1,309 @@
+use crate::time::format_time;
+use crate::MAX_JOURNAL_RECORDS;
+use crate::NO_PARTICIPANT;
+
+use std::io::Write;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JournalOp {
+    EventTriggered,
+    EventCreated,
+    ParticipantJoined,
+    ParticipantLeft,
+    LockAcquired,
+    // Heartbeat found older than the stale threshold
+    ParticipantStale,
+}
+
+impl JournalOp {
+    fn to_raw(self) -> u32 {
+        match self {
+            JournalOp::EventTriggered => 1,
+            JournalOp::EventCreated => 2,
+            JournalOp::ParticipantJoined => 3,
+            JournalOp::ParticipantLeft => 4,
+            JournalOp::LockAcquired => 5,
+            JournalOp::ParticipantStale => 6,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(JournalOp::EventTriggered),
+            2 => Some(JournalOp::EventCreated),
+            3 => Some(JournalOp::ParticipantJoined),
+            4 => Some(JournalOp::ParticipantLeft),
+            5 => Some(JournalOp::LockAcquired),
+            6 => Some(JournalOp::ParticipantStale),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JournalOp::EventTriggered => "event_triggered",
+            JournalOp::EventCreated => "event_created",
+            JournalOp::ParticipantJoined => "participant_joined",
+            JournalOp::ParticipantLeft => "participant_left",
+            JournalOp::LockAcquired => "lock_acquired",
+            JournalOp::ParticipantStale => "participant_stale",
+        }
+    }
+}
+
+// C representation
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct JournalRecord {
+    // Position in the journal plus one, 0 while the record is being written
+    sequence: u64,
+    time_ns: u64,
+    participant_id: u64,
+    // Event or lock the operation applies to
+    object_id: u64,
+    pid: u32,
+    op: u32,
+}
+
+impl JournalRecord {
+    fn new() -> Self {
+        JournalRecord {
+            sequence: 0,
+            time_ns: 0,
+            participant_id: NO_PARTICIPANT,
+            object_id: u64::MAX,
+            pid: 0,
+            op: 0,
+        }
+    }
+
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence - 1
+    }
+
+    pub fn get_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.time_ns)
+    }
+
+    pub fn get_op(&self) -> Option<JournalOp> {
+        JournalOp::from_raw(self.op)
+    }
+
+    // None when the operation was not done on behalf of a participant
+    pub fn get_participant_id(&self) -> Option<u64> {
+        if self.participant_id == NO_PARTICIPANT {
+            return None;
+        }
+        Some(self.participant_id)
+    }
+
+    // Event or lock id, None for participant operations
+    pub fn get_object_id(&self) -> Option<u64> {
+        if self.object_id == u64::MAX {
+            return None;
+        }
+        Some(self.object_id)
+    }
+
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut line = format!(
+            "{} #{} {}",
+            format_time(self.get_time()),
+            self.get_sequence(),
+            self.get_op().map(|op| op.as_str()).unwrap_or("unknown")
+        );
+        if let Some(participant_id) = self.get_participant_id() {
+            line += &format!(" participant={}", participant_id);
+        }
+        if let Some(object_id) = self.get_object_id() {
+            line += &format!(" object={}", object_id);
+        }
+        line + &format!(" pid={}", self.pid)
+    }
+}
+
+// Ring buffer of the last operations done in a group, stored in the
+// directory. Appending is lock free so it can be done while holding any of
+// the group locks; readers skip the records being overwritten.
+//
+// C representation
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Journal {
+    enabled: u32,
+    padding: u32,
+    // Number of records ever appended
+    head: u64,
+    records: [JournalRecord; MAX_JOURNAL_RECORDS],
+}
+
+impl Journal {
+    pub(crate) fn new() -> Self {
+        Journal {
+            enabled: 0,
+            padding: 0,
+            head: 0,
+            records: [JournalRecord::new(); MAX_JOURNAL_RECORDS],
+        }
+    }
+
+    fn enabled_atom<'a>(journal: *mut Journal) -> &'a AtomicU32 {
+        unsafe { AtomicU32::from_ptr(std::ptr::addr_of_mut!((*journal).enabled)) }
+    }
+
+    fn head_atom<'a>(journal: *mut Journal) -> &'a AtomicU64 {
+        unsafe { AtomicU64::from_ptr(std::ptr::addr_of_mut!((*journal).head)) }
+    }
+
+    fn sequence_atom<'a>(record: *mut JournalRecord) -> &'a AtomicU64 {
+        unsafe { AtomicU64::from_ptr(std::ptr::addr_of_mut!((*record).sequence)) }
+    }
+
+    pub(crate) fn set_enabled(journal: *mut Journal, enabled: bool) {
+        Journal::enabled_atom(journal).store(enabled as u32, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_enabled(journal: *mut Journal) -> bool {
+        Journal::enabled_atom(journal).load(Ordering::SeqCst) != 0
+    }
+
+    // Does nothing unless the journal is enabled
+    pub(crate) fn append(
+        journal: *mut Journal,
+        op: JournalOp,
+        participant_id: u64,
+        object_id: u64,
+    ) {
+        if !Journal::is_enabled(journal) {
+            return;
+        }
+        let position = Journal::head_atom(journal).fetch_add(1, Ordering::SeqCst);
+        let record = unsafe {
+            std::ptr::addr_of_mut!((*journal).records[position as usize % MAX_JOURNAL_RECORDS])
+        };
+
+        let sequence = Journal::sequence_atom(record);
+        sequence.store(0, Ordering::SeqCst);
+        fence(Ordering::Release);
+        unsafe {
+            (*record).time_ns = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64;
+            (*record).participant_id = participant_id;
+            (*record).object_id = object_id;
+            (*record).pid = std::process::id();
+            (*record).op = op.to_raw();
+        }
+        sequence.store(position + 1, Ordering::Release);
+    }
+
+    // Records still in the ring, oldest first
+    pub(crate) fn records(journal: *mut Journal) -> Vec<JournalRecord> {
+        let head = Journal::head_atom(journal).load(Ordering::SeqCst);
+        let first = head.saturating_sub(MAX_JOURNAL_RECORDS as u64);
+
+        let mut records = Vec::new();
+        for position in first..head {
+            let slot = position as usize % MAX_JOURNAL_RECORDS;
+            let record = unsafe { std::ptr::addr_of_mut!((*journal).records[slot]) };
+            let sequence = Journal::sequence_atom(record);
+            if sequence.load(Ordering::Acquire) != position + 1 {
+                continue;
+            }
+            let copy = unsafe { std::ptr::read_volatile(record) };
+            fence(Ordering::Acquire);
+            // Overwritten while copying
+            if sequence.load(Ordering::SeqCst) != position + 1 {
+                continue;
+            }
+            records.push(copy);
+        }
+        records
+    }
+}
+
+// Writes the records to a file, one line each
+pub fn dump(records: &[JournalRecord], path: &str) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|e| format!("Cannot create {}: {}", path, e))?;
+    let mut writer = std::io::BufWriter::new(file);
+    for record in records {
+        writeln!(writer, "{}", record.to_text())
+            .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    }
+    writer
+        .flush()
+        .map_err(|e| format!("Cannot write {}: {}", path, e))
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+#[cfg(test)]
+use crate::group::GroupOptions;
+#[cfg(test)]
+use crate::observer::Observer;
+
+#[test]
+fn test_journal() {
+    let mut coordinator = Coordinator::new("test_journal").unwrap();
+    let participant_id = coordinator.add_participant("test_untracked").unwrap();
+    assert!(!coordinator.is_journal_enabled());
+    assert!(coordinator.journal().is_empty());
+
+    coordinator.set_journal_enabled(true);
+    let participant_id2 = coordinator.add_participant("test_tracked").unwrap();
+    let mut event = coordinator
+        .add_event(participant_id2, "test_event")
+        .unwrap();
+    event.trigger(1).unwrap();
+    let mut mutex = coordinator.mutex(participant_id, "test_lock").unwrap();
+    drop(mutex.lock());
+
+    let records = Observer::open("test_journal", &GroupOptions::new())
+        .unwrap()
+        .journal();
+    let ops: Vec<Option<JournalOp>> = records.iter().map(|r| r.get_op()).collect();
+    assert_eq!(
+        ops,
+        vec![
+            Some(JournalOp::ParticipantJoined),
+            Some(JournalOp::EventCreated),
+            Some(JournalOp::EventTriggered),
+            Some(JournalOp::LockAcquired)
+        ]
+    );
+    assert_eq!(records[2].get_participant_id(), Some(participant_id2));
+    assert_eq!(records[2].get_object_id(), Some(event.id()));
+    assert_eq!(records[3].get_object_id(), Some(mutex.get_id()));
+    assert_eq!(records[3].get_pid(), std::process::id());
+
+    let path = std::env::temp_dir().join("mpevent_test_journal.log");
+    dump(&records, path.to_str().unwrap()).unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(text.lines().count(), 4);
+    assert!(text.contains("#2 event_triggered participant=1 object=0"));
+    let _ = std::fs::remove_file(path);
+
+    drop(mutex);
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_journal_wraps() {
+    let mut journal = Box::new(Journal::new());
+    let journal: *mut Journal = &mut *journal;
+    Journal::set_enabled(journal, true);
+    for i in 0..MAX_JOURNAL_RECORDS as u64 + 10 {
+        Journal::append(journal, JournalOp::EventTriggered, 0, i);
+    }
+    let records = Journal::records(journal);
+    assert_eq!(records.len(), MAX_JOURNAL_RECORDS);
+    assert_eq!(records[0].get_sequence(), 10);
+    assert_eq!(records[0].get_object_id(), Some(10));
+}
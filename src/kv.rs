@@ -0,0 +1,116 @@
+use crate::{MAX_KV_KEY_SIZE, MAX_KV_VALUE_SIZE};
+
+// Per-key change events are regular events named after this prefix and the key
+pub const KV_EVENT_PREFIX: &str = "mpevent_kv_";
+
+pub fn kv_event_name(key: &str) -> String {
+    KV_EVENT_PREFIX.to_string() + key
+}
+
+// C representation
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct KeyValue {
+    version: u64,
+    key: [u8; MAX_KV_KEY_SIZE],
+    value: [u8; MAX_KV_VALUE_SIZE],
+}
+
+impl Default for KeyValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyValue {
+    pub fn new() -> Self {
+        KeyValue {
+            version: 0,
+            key: [0; MAX_KV_KEY_SIZE],
+            value: [0; MAX_KV_VALUE_SIZE],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key[0] == 0
+    }
+
+    pub fn get_key(&self) -> String {
+        let vkey: Vec<u8> = self.key.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8(vkey).unwrap()
+    }
+
+    pub fn get_value(&self) -> String {
+        let vvalue: Vec<u8> = self
+            .value
+            .iter()
+            .take_while(|&&c| c != 0)
+            .cloned()
+            .collect();
+        String::from_utf8(vvalue).unwrap()
+    }
+
+    // Bumped on every write so readers can tell two equal values apart
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        if key.is_empty() || key.len() >= MAX_KV_KEY_SIZE {
+            return Err("Invalid key length");
+        }
+        if value.len() >= MAX_KV_VALUE_SIZE {
+            return Err("Value too long");
+        }
+        let version = self.version;
+        *self = KeyValue::new();
+        self.version = version + 1;
+        self.key[..key.len()].copy_from_slice(key.as_bytes());
+        self.value[..value.len()].copy_from_slice(value.as_bytes());
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        let version = self.version;
+        *self = KeyValue::new();
+        self.version = version + 1;
+    }
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_kv_store() {
+    let mut coordinator = Coordinator::new("test_kv_store").unwrap();
+    assert_eq!(coordinator.kv_get("log_level"), None);
+    coordinator.kv_set("log_level", "debug").unwrap();
+    assert_eq!(coordinator.kv_get("log_level"), Some(String::from("debug")));
+    coordinator.kv_set("log_level", "info").unwrap();
+    assert_eq!(coordinator.kv_get("log_level"), Some(String::from("info")));
+    assert!(coordinator.kv_set("", "value").is_err());
+
+    assert_eq!(coordinator.kv_delete("log_level"), Ok(true));
+    assert_eq!(coordinator.kv_delete("log_level"), Ok(false));
+    assert_eq!(coordinator.kv_get("log_level"), None);
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_kv_watch() {
+    let mut coordinator = Coordinator::new_clean("test_kv_watch").unwrap();
+    let mut watcher = coordinator.kv_watch("leader").unwrap();
+
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_kv_watch").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        coordinator.kv_set("leader", "node-1").unwrap();
+    });
+
+    watcher.wait().unwrap();
+    assert_eq!(coordinator.kv_get("leader"), Some(String::from("node-1")));
+    handle.join().unwrap();
+
+    let _ = coordinator.close(true);
+}
@@ -2,10 +2,69 @@ const MAX_PARTICIPANTS: usize = 64;
 const MAX_EVENT_NAME_SIZE: usize = 256;
 const MAX_EVENTS: usize = 64;
 const MAX_PARTICIPANT_NAME_SIZE: usize = 64;
+const MAX_PARTICIPANT_LABELS: usize = 8;
+const MAX_LABEL_SIZE: usize = 64;
+const MAX_EXE_NAME_SIZE: usize = 64;
+const MAX_LOCKS: usize = 64;
+const MAX_LOCK_NAME_SIZE: usize = 64;
+const MAX_CONDVARS: usize = 64;
+const MAX_COUNTERS: usize = 64;
+const MAX_COUNTER_NAME_SIZE: usize = 64;
+const MAX_KV_ENTRIES: usize = 64;
+const MAX_KV_KEY_SIZE: usize = 64;
+const MAX_KV_VALUE_SIZE: usize = 256;
+const MAX_STATE_CHANNELS: usize = 32;
+const MAX_STATE_CHANNEL_NAME_SIZE: usize = 64;
+const MAX_STATE_SIZE: usize = 256;
+const MAX_ROLES: usize = 16;
+const MAX_ROLE_NAME_SIZE: usize = 64;
+const MAX_ATTACHED_PIDS: usize = 64;
+const MAX_GROUP_NAME_SIZE: usize = 128;
+const MAX_EVENT_ACL_ENTRIES: usize = 8;
+const MAX_JOURNAL_RECORDS: usize = 1024;
 
 pub const BUILTIN_EVENT_NEW_PARTICIPANT: &str = "mpevent_new_participant";
 pub const BUILTIN_EVENT_NEW_EVENT: &str = "mpevent_new_event";
+pub const BUILTIN_EVENT_LEADER_CHANGED: &str = "mpevent_leader_changed";
+pub const BUILTIN_EVENT_PARTICIPANT_STALE: &str = "mpevent_participant_stale";
 
+const BUILTIN_EVENTS: [&str; 4] = [
+    BUILTIN_EVENT_NEW_PARTICIPANT,
+    BUILTIN_EVENT_NEW_EVENT,
+    BUILTIN_EVENT_LEADER_CHANGED,
+    BUILTIN_EVENT_PARTICIPANT_STALE,
+];
+
+// FUTEX_WAKE takes the number of waiters as a signed int, so u32::MAX would
+// only wake one of them
+const WAKE_ALL: u32 = i32::MAX as u32;
+
+// Owner value used when a shared object is not held by any participant
+pub const NO_PARTICIPANT: u64 = u64::MAX;
+
+// Returned when the group segment or its policy does not allow the caller in
+pub const ERROR_PERMISSION_DENIED: &str = "Permission denied";
+
+// Returned when joining a group that does not exist
+pub const ERROR_GROUP_NOT_FOUND: &str = "Group not found";
+
+// Returned when a participant already owns as many events as its quota allows
+pub const ERROR_QUOTA_EXCEEDED: &str = "Quota exceeded";
+
+pub mod condvar;
 pub mod coordinator;
+pub mod counter;
+pub mod election;
 pub mod event;
+pub mod group;
+mod hash;
+pub mod heartbeat;
+pub mod journal;
+pub mod kv;
+pub mod lock;
+pub mod observer;
 pub mod participant;
+mod segment;
+pub mod state;
+mod time;
+pub mod watch;
@@ -0,0 +1,369 @@
+use crate::coordinator::segment_journal;
+use crate::journal::{Journal, JournalOp};
+use crate::segment::Segment;
+use crate::MAX_LOCK_NAME_SIZE;
+use crate::NO_PARTICIPANT;
+use crate::WAKE_ALL;
+
+use rufutex::rufutex::SharedFutex;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const WRITER_LOCKED: u32 = 1 << 31;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LockKind {
+    Mutex,
+    RwLock,
+}
+
+impl LockKind {
+    fn to_raw(self) -> u32 {
+        match self {
+            LockKind::Mutex => 1,
+            LockKind::RwLock => 2,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(LockKind::Mutex),
+            2 => Some(LockKind::RwLock),
+            _ => None,
+        }
+    }
+}
+
+// C representation
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Lock {
+    id: u64,
+    owner: u64,
+    kind: u32,
+    state: u32,
+    name: [u8; MAX_LOCK_NAME_SIZE],
+}
+
+impl Default for Lock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lock {
+    pub fn new() -> Self {
+        Lock {
+            id: 0,
+            owner: NO_PARTICIPANT,
+            kind: 0,
+            state: 0,
+            name: [0; MAX_LOCK_NAME_SIZE],
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8(vname).unwrap()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_kind(&self) -> Option<LockKind> {
+        LockKind::from_raw(self.kind)
+    }
+
+    // Participant holding the mutex or the write side of the rwlock
+    pub fn get_owner(&self) -> Option<u64> {
+        if self.owner == NO_PARTICIPANT {
+            return None;
+        }
+        Some(self.owner)
+    }
+
+    pub fn get_readers(&self) -> u32 {
+        if self.get_kind() != Some(LockKind::RwLock) {
+            return 0;
+        }
+        self.state & !WRITER_LOCKED
+    }
+
+    pub(crate) fn init(&mut self, id: u64, name: &str, kind: LockKind) -> Result<(), &'static str> {
+        if name.len() >= MAX_LOCK_NAME_SIZE {
+            return Err("Name too long");
+        }
+        *self = Lock::new();
+        self.id = id;
+        self.kind = kind.to_raw();
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+}
+
+fn state_futex(lock: *mut Lock) -> SharedFutex {
+    let ptr_state = unsafe { std::ptr::addr_of_mut!((*lock).state) };
+    SharedFutex::new(ptr_state as *mut libc::c_void)
+}
+
+fn state_atom<'a>(lock: *mut Lock) -> &'a AtomicU32 {
+    unsafe { AtomicU32::from_ptr(std::ptr::addr_of_mut!((*lock).state)) }
+}
+
+fn record_acquired(segment: &Segment, lock: *mut Lock, participant_id: u64) {
+    let id = unsafe { (*lock).get_id() };
+    Journal::append(
+        segment_journal(segment),
+        JournalOp::LockAcquired,
+        participant_id,
+        id,
+    );
+}
+
+pub struct SharedMutex {
+    // Keeps the mapping holding the lock alive
+    segment: Rc<Segment>,
+    lock: *mut Lock,
+    futex: SharedFutex,
+    participant_id: u64,
+}
+
+impl SharedMutex {
+    pub(crate) fn new(segment: Rc<Segment>, lock: *mut Lock, participant_id: u64) -> Self {
+        SharedMutex {
+            segment,
+            lock,
+            futex: state_futex(lock),
+            participant_id,
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        unsafe { (*self.lock).get_name() }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        unsafe { (*self.lock).get_id() }
+    }
+
+    pub fn lock(&mut self) -> SharedMutexGuard<'_> {
+        self.futex.lock();
+        unsafe {
+            (*self.lock).owner = self.participant_id;
+        }
+        record_acquired(&self.segment, self.lock, self.participant_id);
+        SharedMutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&mut self) -> Option<SharedMutexGuard<'_>> {
+        let ret = state_atom(self.lock).compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst);
+        if ret.is_err() {
+            return None;
+        }
+        unsafe {
+            (*self.lock).owner = self.participant_id;
+        }
+        record_acquired(&self.segment, self.lock, self.participant_id);
+        Some(SharedMutexGuard { mutex: self })
+    }
+
+    fn release(&mut self) {
+        unsafe {
+            (*self.lock).owner = NO_PARTICIPANT;
+        }
+        self.futex.unlock(1);
+    }
+}
+
+pub struct SharedMutexGuard<'a> {
+    mutex: &'a mut SharedMutex,
+}
+
+impl<'a> SharedMutexGuard<'a> {
+    pub(crate) fn get_mutex_id(&self) -> u64 {
+        self.mutex.get_id()
+    }
+
+    // Releases the lock and hands back the mutex so it can be locked again
+    pub(crate) fn unlock(self) -> &'a mut SharedMutex {
+        let mut guard = std::mem::ManuallyDrop::new(self);
+        let mutex: *mut SharedMutex = &mut *guard.mutex;
+        unsafe {
+            (*mutex).release();
+            &mut *mutex
+        }
+    }
+}
+
+impl Drop for SharedMutexGuard<'_> {
+    fn drop(&mut self) {
+        self.mutex.release();
+    }
+}
+
+pub struct SharedRwLock {
+    // Keeps the mapping holding the lock alive
+    segment: Rc<Segment>,
+    lock: *mut Lock,
+    futex: SharedFutex,
+    participant_id: u64,
+}
+
+impl SharedRwLock {
+    pub(crate) fn new(segment: Rc<Segment>, lock: *mut Lock, participant_id: u64) -> Self {
+        SharedRwLock {
+            segment,
+            lock,
+            futex: state_futex(lock),
+            participant_id,
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        unsafe { (*self.lock).get_name() }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        unsafe { (*self.lock).get_id() }
+    }
+
+    pub fn read(&mut self) -> SharedRwLockReadGuard<'_> {
+        let atom = state_atom(self.lock);
+        loop {
+            let state = atom.load(Ordering::SeqCst);
+            if state & WRITER_LOCKED == 0 {
+                if atom
+                    .compare_exchange(state, state + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    break;
+                }
+                continue;
+            }
+            self.futex.wait(state);
+        }
+        record_acquired(&self.segment, self.lock, self.participant_id);
+        SharedRwLockReadGuard { rwlock: self }
+    }
+
+    pub fn write(&mut self) -> SharedRwLockWriteGuard<'_> {
+        let atom = state_atom(self.lock);
+        loop {
+            let state = atom.load(Ordering::SeqCst);
+            if state == 0 {
+                if atom
+                    .compare_exchange(0, WRITER_LOCKED, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    break;
+                }
+                continue;
+            }
+            self.futex.wait(state);
+        }
+        unsafe {
+            (*self.lock).owner = self.participant_id;
+        }
+        record_acquired(&self.segment, self.lock, self.participant_id);
+        SharedRwLockWriteGuard { rwlock: self }
+    }
+}
+
+pub struct SharedRwLockReadGuard<'a> {
+    rwlock: &'a mut SharedRwLock,
+}
+
+impl Drop for SharedRwLockReadGuard<'_> {
+    fn drop(&mut self) {
+        let previous = state_atom(self.rwlock.lock).fetch_sub(1, Ordering::SeqCst);
+        if previous == 1 {
+            self.rwlock.futex.post(WAKE_ALL);
+        }
+    }
+}
+
+pub struct SharedRwLockWriteGuard<'a> {
+    rwlock: &'a mut SharedRwLock,
+}
+
+impl Drop for SharedRwLockWriteGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.rwlock.lock).owner = NO_PARTICIPANT;
+        }
+        state_atom(self.rwlock.lock).store(0, Ordering::SeqCst);
+        self.rwlock.futex.post(WAKE_ALL);
+    }
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_shared_mutex() {
+    let mut coordinator = Coordinator::new("test_shared_mutex").unwrap();
+    let participant_id = coordinator.add_participant("test_mutex_owner").unwrap();
+    let mut mutex = coordinator.mutex(participant_id, "test_mutex").unwrap();
+    assert_eq!(mutex.get_name(), "test_mutex");
+    assert_eq!(coordinator.get_number_of_locks(), 1);
+
+    {
+        let _guard = mutex.lock();
+        let lock = coordinator.get_lock(0).unwrap();
+        assert_eq!(lock.get_kind(), Some(LockKind::Mutex));
+        assert_eq!(lock.get_owner(), Some(participant_id));
+
+        let mut mutex2 = coordinator.mutex(participant_id, "test_mutex").unwrap();
+        assert!(mutex2.try_lock().is_none());
+    }
+    assert_eq!(coordinator.get_lock(0).unwrap().get_owner(), None);
+    assert!(coordinator.rwlock(participant_id, "test_mutex").is_err());
+
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_shared_mutex").unwrap();
+        let mut mutex = coordinator.mutex(1, "test_mutex").unwrap();
+        let _guard = mutex.lock();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    });
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let _guard = mutex.lock();
+    assert_eq!(
+        coordinator.get_lock(0).unwrap().get_owner(),
+        Some(participant_id)
+    );
+    handle.join().unwrap();
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_shared_rwlock() {
+    let mut coordinator = Coordinator::new("test_shared_rwlock").unwrap();
+    let participant_id = coordinator.add_participant("test_rwlock_owner").unwrap();
+    let mut reader1 = coordinator.rwlock(participant_id, "test_rwlock").unwrap();
+    let mut reader2 = coordinator.rwlock(participant_id, "test_rwlock").unwrap();
+    assert_eq!(coordinator.get_number_of_locks(), 1);
+
+    {
+        let _r1 = reader1.read();
+        let _r2 = reader2.read();
+        let lock = coordinator.get_lock(0).unwrap();
+        assert_eq!(lock.get_kind(), Some(LockKind::RwLock));
+        assert_eq!(lock.get_readers(), 2);
+        assert_eq!(lock.get_owner(), None);
+    }
+
+    let mut writer = coordinator.rwlock(participant_id, "test_rwlock").unwrap();
+    {
+        let _w = writer.write();
+        assert_eq!(
+            coordinator.get_lock(0).unwrap().get_owner(),
+            Some(participant_id)
+        );
+    }
+    let _r1 = reader1.read();
+    assert_eq!(coordinator.get_lock(0).unwrap().get_readers(), 1);
+
+    let _ = coordinator.close(true);
+}
@@ -0,0 +1,135 @@
+use crate::coordinator::{segment_journal, Directory, Participant};
+use crate::event::Event;
+use crate::group::GroupOptions;
+use crate::journal::{Journal, JournalRecord};
+use crate::segment::Segment;
+
+use std::time::{Duration, Instant};
+
+// Read-only view of a group for monitoring tools. The directory is mapped
+// without write access, so observers never take a participant slot, are not
+// counted as attached and cannot add or trigger events.
+//
+// The directory lock cannot be taken read-only, so snapshots are best effort.
+pub struct Observer {
+    mem_path: String,
+    segment: Segment,
+}
+
+impl Observer {
+    pub fn open(mem_path: &str, options: &GroupOptions) -> Result<Self, String> {
+        let segment = Segment::open_read_only(options, mem_path, std::mem::size_of::<Directory>())?;
+        let directory = segment.as_ptr() as *const Directory;
+        unsafe {
+            if !(*directory).is_valid() {
+                return Err(String::from("Shared memory is not a group"));
+            }
+            (*directory).check_access(&segment, libc::geteuid())?;
+        }
+
+        Ok(Observer {
+            mem_path: mem_path.to_string(),
+            segment,
+        })
+    }
+
+    pub(crate) fn directory(&self) -> &Directory {
+        unsafe { &*(self.segment.as_ptr() as *const Directory) }
+    }
+
+    pub fn get_path(&self) -> String {
+        self.mem_path.clone()
+    }
+
+    pub fn participants(&self) -> std::vec::IntoIter<Participant> {
+        self.directory().participants_snapshot().into_iter()
+    }
+
+    pub fn events(&self) -> std::vec::IntoIter<Event> {
+        self.directory().events_snapshot().into_iter()
+    }
+
+    pub fn find_participant(&self, name: &str) -> Option<Participant> {
+        self.participants().find(|p| p.get_name() == name)
+    }
+
+    pub fn find_event(&self, name: &str) -> Option<Event> {
+        let name = self.mem_path.to_string() + "_" + name;
+        self.events().find(|e| e.get_name() == name)
+    }
+
+    // Records still in the journal of the group, oldest first
+    pub fn journal(&self) -> Vec<JournalRecord> {
+        Journal::records(segment_journal(&self.segment))
+    }
+
+    // Waits for the next trigger of the event without consuming it, so the
+    // regular waiters still get it. Returns false if the timeout expired.
+    pub fn wait_event(&self, name: &str, timeout: Option<Duration>) -> Result<bool, String> {
+        let id = self.find_event(name).ok_or("Event not found")?.get_id();
+        let event = self.directory().get_event(id).ok_or("Event not found")? as *const Event;
+        let generation = Event::generation_atom(event);
+        let mut futex = Event::generation_futex(event);
+
+        let start = generation.load(std::sync::atomic::Ordering::SeqCst);
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        while generation.load(std::sync::atomic::Ordering::SeqCst) == start {
+            match deadline {
+                None => {
+                    futex.wait(start);
+                }
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    let remaining = deadline - now;
+                    let timeout_spec = libc::timespec {
+                        tv_sec: remaining.as_secs() as i64,
+                        tv_nsec: remaining.subsec_nanos() as i64,
+                    };
+                    futex.wait_with_timeout(start, timeout_spec);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_observer() {
+    let mut coordinator = Coordinator::new("test_observer").unwrap();
+    let participant_id = coordinator.add_participant("test_publisher").unwrap();
+    let mut event = coordinator.add_event(participant_id, "test_event").unwrap();
+
+    let observer = Observer::open("test_observer", &GroupOptions::new()).unwrap();
+    assert_eq!(observer.participants().count(), 1);
+    assert!(observer.find_participant("test_publisher").is_some());
+    assert_eq!(
+        observer.find_event("test_event").unwrap().get_id(),
+        event.id()
+    );
+    assert_eq!(coordinator.get_attach_count(), 1);
+    assert_eq!(
+        observer.wait_event("test_event", Some(Duration::from_millis(10))),
+        Ok(false)
+    );
+    assert!(observer.wait_event("missing", None).is_err());
+
+    let handle = std::thread::spawn(move || {
+        let observer = Observer::open("test_observer", &GroupOptions::new()).unwrap();
+        observer.wait_event("test_event", None).unwrap()
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    event.trigger(1).unwrap();
+    assert!(handle.join().unwrap());
+
+    // The trigger is still pending for regular waiters
+    assert!(event.is_signaled());
+    assert!(Observer::open("test_observer_missing", &GroupOptions::new()).is_err());
+
+    let _ = coordinator.close(true);
+}
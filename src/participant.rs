@@ -1,5 +1,12 @@
+use crate::condvar::Condvar;
 use crate::coordinator::Coordinator;
-use crate::event::Event;
+use crate::counter::{SharedCounter, SharedGauge};
+use crate::event::{EventAccess, EventHandle};
+use crate::group::GroupOptions;
+use crate::heartbeat::Heartbeat;
+use crate::journal::JournalOp;
+use crate::lock::{SharedMutex, SharedRwLock};
+use crate::state::{StateChannel, StateType};
 use log::debug;
 use std::collections::HashMap;
 
@@ -7,29 +14,61 @@ pub struct Participant<'a> {
     id: u64,
     name: String,
     coordinator: Coordinator,
-    map_events: HashMap<String, rufutex::rufutex::SharedFutex>,
+    map_events: HashMap<String, EventHandle>,
     on_new_event: Box<dyn FnMut(u64) + 'a>,
     on_new_participant: Box<dyn FnMut(u64) + 'a>,
+    heartbeat: Option<Heartbeat>,
+    left: bool,
 }
 
 impl<'a> Participant<'a> {
-    pub fn new(name: &str, mem_path: &str) -> Self {
-        let mut coordinator = Coordinator::new(mem_path);
-        let ret = coordinator.add_participant(name);
-        if ret.is_err() {
-            panic!("Failed to add participant");
-        }
+    pub fn new(name: &str, mem_path: &str) -> Result<Self, String> {
+        Participant::with_coordinator(name, Coordinator::new(mem_path)?)
+    }
+
+    // Joins the group of a coordinator created or opened with custom options
+    pub fn with_coordinator(name: &str, mut coordinator: Coordinator) -> Result<Self, String> {
+        let id = coordinator.add_participant(name)?;
 
         let map_events = HashMap::new();
 
-        Participant {
-            id: ret.unwrap(),
+        Ok(Participant {
+            id,
             name: name.to_string(),
             coordinator,
             map_events,
             on_new_event: Box::new(|_| {}),
             on_new_participant: Box::new(|_| {}),
-        }
+            heartbeat: None,
+            left: false,
+        })
+    }
+
+    // Joins an existing group keeping the id and owned events of a previous run
+    pub fn reclaim(name: &str, mem_path: &str, token: &str) -> Result<Self, String> {
+        Participant::reclaim_with_options(name, mem_path, token, &GroupOptions::new())
+    }
+
+    // Same as `reclaim` for groups opened with custom options, e.g. file-backed
+    pub fn reclaim_with_options(
+        name: &str,
+        mem_path: &str,
+        token: &str,
+        options: &GroupOptions,
+    ) -> Result<Self, String> {
+        let mut coordinator = Coordinator::open(mem_path, options)?;
+        let id = coordinator.reclaim_participant(name, token)?;
+
+        Ok(Participant {
+            id,
+            name: name.to_string(),
+            coordinator,
+            map_events: HashMap::new(),
+            on_new_event: Box::new(|_| {}),
+            on_new_participant: Box::new(|_| {}),
+            heartbeat: None,
+            left: false,
+        })
     }
 
     pub fn get_name(&self) -> String {
@@ -44,14 +83,72 @@ impl<'a> Participant<'a> {
         self.id = id;
     }
 
+    pub fn set_label(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.coordinator.set_participant_label(self.id, key, value)
+    }
+
     pub fn get_coordinator(&self) -> &Coordinator {
         &self.coordinator
     }
 
-    fn get_or_create_event(
-        &mut self,
-        event_name: &str,
-    ) -> Result<&mut rufutex::rufutex::SharedFutex, String> {
+    pub fn mutex(&mut self, name: &str) -> Result<SharedMutex, String> {
+        self.coordinator.mutex(self.id, name)
+    }
+
+    pub fn rwlock(&mut self, name: &str) -> Result<SharedRwLock, String> {
+        self.coordinator.rwlock(self.id, name)
+    }
+
+    pub fn condvar(&mut self, name: &str, mutex: &SharedMutex) -> Result<Condvar, String> {
+        self.coordinator.condvar(name, mutex)
+    }
+
+    pub fn counter(&mut self, name: &str) -> Result<SharedCounter, String> {
+        self.coordinator.counter(name)
+    }
+
+    pub fn gauge(&mut self, name: &str) -> Result<SharedGauge, String> {
+        self.coordinator.gauge(name)
+    }
+
+    pub fn state_channel<T: StateType>(&mut self, name: &str) -> Result<StateChannel<T>, String> {
+        self.coordinator.state_channel(name)
+    }
+
+    pub fn wait_on_key(&mut self, key: &str) -> Result<(), String> {
+        self.wait_on_event(&crate::kv::kv_event_name(key))
+    }
+
+    // Blocks until this participant becomes the leader of the role
+    pub fn campaign(&mut self, role: &str) -> Result<(), String> {
+        loop {
+            if self.coordinator.campaign(self.id, role)? {
+                return Ok(());
+            }
+            let _ = self
+                .coordinator
+                .wait_leader_change(role, crate::election::LEADER_LIVENESS_POLL);
+        }
+    }
+
+    pub fn try_campaign(&mut self, role: &str) -> Result<bool, String> {
+        self.coordinator.campaign(self.id, role)
+    }
+
+    pub fn resign(&mut self, role: &str) -> Result<(), String> {
+        self.coordinator.resign(self.id, role)
+    }
+
+    pub fn is_leader(&mut self, role: &str) -> bool {
+        self.coordinator.current_leader(role) == Some(self.id)
+    }
+
+    pub fn wait_on_leader_change(&mut self, role: &str) -> Result<Option<u64>, String> {
+        self.coordinator
+            .wait_leader_change(role, std::time::Duration::MAX)
+    }
+
+    fn get_or_create_event(&mut self, event_name: &str) -> Result<&mut EventHandle, String> {
         if self.map_events.contains_key(event_name) {
             debug!("Event {} already exists in shared memory", event_name);
             let ev = self.map_events.get_mut(event_name).unwrap();
@@ -78,19 +175,28 @@ impl<'a> Participant<'a> {
             return Err(String::from("Error getting or creating event"));
         }
 
-        let value = 1;
-        let event: &mut rufutex::rufutex::SharedFutex = ret.unwrap();
-        event.post_with_value(value, number_of_waiters);
+        let event: &mut EventHandle = ret.unwrap();
+        event.trigger(number_of_waiters)?;
 
         Ok(())
     }
 
+    // Only allowed on events created by this participant
+    pub fn set_event_access(
+        &mut self,
+        event_name: &str,
+        trigger: EventAccess,
+        wait: EventAccess,
+        allowed: &[&str],
+    ) -> Result<(), String> {
+        self.get_or_create_event(event_name)?;
+        self.coordinator
+            .set_event_access(self.id, event_name, trigger, wait, allowed)
+    }
+
     pub fn wait_on_internal_event(&mut self, event_name: &str) -> Result<(), String> {
         debug!("Waiting on internal event {}", event_name);
-        let event_name = self.get_coordinator().get_path() + "_" + event_name;
-        let mut event = Event::new();
-        event.set_name(&event_name).unwrap();
-        let shared_futex = event.get_waitable();
+        let shared_futex = self.coordinator.builtin_waitable(event_name);
         if shared_futex.is_none() {
             return Err(String::from("Error getting waitable"));
         }
@@ -113,19 +219,13 @@ impl<'a> Participant<'a> {
             return Err(String::from("Error getting or creating event"));
         }
 
-        let event: &mut rufutex::rufutex::SharedFutex = ret.unwrap();
+        let event: &mut EventHandle = ret.unwrap();
         debug!(
-            " |-> Waiting on event {} with value {}",
+            " |-> Waiting on event {} signaled {}",
             event_name,
-            event.get_futex_value()
+            event.is_signaled()
         );
-        event.wait(0);
-        let v = event.get_futex_value();
-        if v == 0 {
-            //It was spurious wake up
-            return Err(String::from("Error waiting on event"));
-        }
-        event.set_futex_value(0);
+        event.wait()?;
 
         Ok(())
     }
@@ -139,14 +239,8 @@ impl<'a> Participant<'a> {
         if ret.is_err() {
             return Err(String::from("Error getting or creating event"));
         }
-        let timeout_spec = libc::timespec {
-            tv_sec: timeout.as_secs() as i64,
-            tv_nsec: timeout.subsec_nanos() as i64,
-        };
-
-        let event: &mut rufutex::rufutex::SharedFutex = ret.unwrap();
-        event.wait_with_timeout(0, timeout_spec);
-        event.set_futex_value(0);
+        let event: &mut EventHandle = ret.unwrap();
+        event.wait_timeout(timeout)?;
 
         Ok(())
     }
@@ -164,7 +258,7 @@ impl<'a> Participant<'a> {
             //Check which was the last event
             let last_event_id = self.coordinator.get_last_event_id();
 
-            let ret = self.wait_on_event(crate::BUILTIN_EVENT_NEW_EVENT);
+            let ret = self.wait_on_internal_event(crate::BUILTIN_EVENT_NEW_EVENT);
             if ret.is_err() {
                 //Spurious wake up
                 debug!("Spurious wake up");
@@ -243,22 +337,58 @@ impl<'a> Participant<'a> {
         Ok(())
     }
 
+    // Bumps the heartbeat from a background thread every period
+    pub fn enable_heartbeat(
+        &mut self,
+        period: std::time::Duration,
+        threshold: std::time::Duration,
+    ) -> Result<(), String> {
+        self.disable_heartbeat();
+        self.coordinator.set_heartbeat(self.id, period, threshold)?;
+        self.heartbeat = Some(Heartbeat::start(&self.coordinator, self.id, period)?);
+        Ok(())
+    }
+
+    pub fn disable_heartbeat(&mut self) {
+        if let Some(mut heartbeat) = self.heartbeat.take() {
+            heartbeat.stop();
+        }
+    }
+
+    // Journals the departure once, whether through close() or drop
+    fn leave(&mut self) {
+        self.disable_heartbeat();
+        if !self.left {
+            self.left = true;
+            self.coordinator
+                .record(JournalOp::ParticipantLeft, self.id, u64::MAX);
+        }
+    }
+
     pub fn close(&mut self) -> Result<(), String> {
-        self.coordinator.close(true)
+        self.leave();
+        self.coordinator.close(false)
+    }
+}
+
+impl Drop for Participant<'_> {
+    fn drop(&mut self) {
+        self.leave();
     }
 }
 
 #[cfg(test)]
 #[test]
 fn test_subscriber() {
-    let mut subscriber = Participant::new("test_subscriber", "test_mem_path");
+    let mut subscriber = Participant::new("test_subscriber", "test_mem_path").unwrap();
     assert_eq!(subscriber.get_id(), 0);
     assert_eq!(subscriber.get_name(), "test_subscriber");
 
     // spawn a thread to wait on the futex
     let handle = std::thread::spawn(move || {
-        let mut subscriber = Participant::new("test_subscriber2", "test_mem_path");
-        assert_eq!(subscriber.get_id(), 0);
+        // Joins the group of the first subscriber instead of resetting it
+        let mut subscriber = Participant::new("test_subscriber2", "test_mem_path").unwrap();
+        assert_eq!(subscriber.get_id(), 1);
         assert_eq!(subscriber.get_name(), "test_subscriber2");
         let ret = subscriber.wait_on_event("test_subscribers");
         assert!(ret.is_ok());
@@ -268,11 +398,69 @@ fn test_subscriber() {
 
     // Sleep for a bit to allow the thread to start
     std::thread::sleep(std::time::Duration::from_millis(100));
-    let ret = subscriber.trigger_event("test_subscribers", u32::max_value());
+    let ret = subscriber.trigger_event("test_subscribers", u32::MAX);
     assert!(ret.is_ok());
     std::thread::sleep(std::time::Duration::from_millis(100));
-    let ret = subscriber.trigger_event("test_subscribers", u32::max_value());
+    let ret = subscriber.trigger_event("test_subscribers", u32::MAX);
     assert!(ret.is_ok());
     handle.join().unwrap();
     let _ = subscriber.close();
 }
+
+#[test]
+fn test_reclaim_with_options() {
+    let root = std::env::temp_dir().join("mpevent_test_reclaim_with_options");
+    let _ = std::fs::create_dir_all(&root);
+    let mut options = GroupOptions::new();
+    options.set_root(root.to_str().unwrap());
+
+    let mut coordinator = Coordinator::create("test_reclaim_with_options", &options).unwrap();
+    assert!(Participant::reclaim("test_service", "test_reclaim_with_options", "secret").is_err());
+    let participant = Participant::reclaim_with_options(
+        "test_service",
+        "test_reclaim_with_options",
+        "secret",
+        &options,
+    )
+    .unwrap();
+    assert_eq!(
+        coordinator
+            .find_participant("test_service")
+            .unwrap()
+            .get_id(),
+        participant.get_id()
+    );
+
+    drop(participant);
+    let _ = coordinator.close(true);
+    let _ = std::fs::remove_dir(&root);
+}
+
+#[test]
+fn test_left_on_drop() {
+    let mut coordinator = Coordinator::new("test_left_on_drop").unwrap();
+    coordinator.set_journal_enabled(true);
+    let participant = Participant::with_coordinator(
+        "test_dropped",
+        Coordinator::new("test_left_on_drop").unwrap(),
+    )
+    .unwrap();
+    let id = participant.get_id();
+    drop(participant);
+    let mut closed = Participant::with_coordinator(
+        "test_closed",
+        Coordinator::new("test_left_on_drop").unwrap(),
+    )
+    .unwrap();
+    closed.close().unwrap();
+    drop(closed);
+
+    let left: Vec<Option<u64>> = coordinator
+        .journal()
+        .iter()
+        .filter(|r| r.get_op() == Some(JournalOp::ParticipantLeft))
+        .map(|r| r.get_participant_id())
+        .collect();
+    assert_eq!(left, vec![Some(id), Some(id + 1)]);
+    let _ = coordinator.close(true);
+}
@@ -0,0 +1,309 @@
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::group::GroupOptions;
+use crate::{ERROR_GROUP_NOT_FOUND, ERROR_PERMISSION_DENIED};
+
+// Mapping of a group segment. Every handle pointing into the segment holds an
+// Rc to it, so the memory is only unmapped once the last handle is dropped.
+//
+// Segments are POSIX shared memory objects unless a root directory is given,
+// in which case they are regular files under it (tmpfs, hugetlbfs, ...).
+// Anonymous segments live on a memfd which is kept open to hand it over.
+pub(crate) struct Segment {
+    ptr: *mut libc::c_void,
+    size: usize,
+    fd: Option<OwnedFd>,
+    owner_uid: u32,
+}
+
+fn c_string(value: &str) -> Result<CString, String> {
+    CString::new(value).map_err(|_| String::from("Invalid shared memory name"))
+}
+
+fn last_error() -> std::io::Error {
+    std::io::Error::last_os_error()
+}
+
+fn open_error(context: &str) -> String {
+    let error = last_error();
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => String::from(ERROR_PERMISSION_DENIED),
+        Some(libc::ENOENT) => String::from(ERROR_GROUP_NOT_FOUND),
+        _ => format!("{}: {}", context, error),
+    }
+}
+
+// Files on hugetlbfs can only be sized in multiples of the huge page size
+fn block_size(fd: RawFd) -> usize {
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(fd, &mut stat) } < 0 || stat.f_bsize <= 0 {
+        return 1;
+    }
+    stat.f_bsize as usize
+}
+
+impl Segment {
+    fn open_fd(options: &GroupOptions, name: &str, flags: i32) -> Result<OwnedFd, String> {
+        let mode = options.get_mode().unwrap_or(libc::S_IRUSR | libc::S_IWUSR);
+
+        let fd = match options.get_root() {
+            None => {
+                let c_name = c_string(name)?;
+                unsafe { libc::shm_open(c_name.as_ptr(), flags | libc::O_CLOEXEC, mode) }
+            }
+            Some(root) => {
+                let c_path = c_string(&root.join(name).to_string_lossy())?;
+                unsafe { libc::open(c_path.as_ptr(), flags | libc::O_CLOEXEC, mode) }
+            }
+        };
+        if fd < 0 {
+            return Err(open_error("Error opening shared memory"));
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    pub(crate) fn open(
+        options: &GroupOptions,
+        name: &str,
+        size: usize,
+        create: bool,
+    ) -> Result<Self, String> {
+        let mut flags = libc::O_RDWR;
+        if create {
+            flags |= libc::O_CREAT;
+        }
+        let fd = Segment::open_fd(options, name, flags)?;
+        let mode = options.get_mode().unwrap_or(libc::S_IRUSR | libc::S_IWUSR);
+
+        // The mode passed to open is filtered by the umask
+        if create
+            && options.get_mode().is_some()
+            && unsafe { libc::fchmod(fd.as_raw_fd(), mode) } < 0
+        {
+            return Err(open_error("Error setting shared memory mode"));
+        }
+        if create {
+            if let Some(gid) = options.get_gid() {
+                if unsafe { libc::fchown(fd.as_raw_fd(), u32::MAX, gid) } < 0 {
+                    return Err(open_error("Error setting shared memory group"));
+                }
+            }
+        }
+
+        Segment::map(fd.as_raw_fd(), size, create, true)
+    }
+
+    // Mapped without write access, stores into it fault
+    pub(crate) fn open_read_only(
+        options: &GroupOptions,
+        name: &str,
+        size: usize,
+    ) -> Result<Self, String> {
+        let fd = Segment::open_fd(options, name, libc::O_RDONLY)?;
+        Segment::map(fd.as_raw_fd(), size, false, false)
+    }
+
+    pub(crate) fn anonymous(name: &str, size: usize) -> Result<Self, String> {
+        let c_name = c_string(name)?;
+        let fd = unsafe { libc::memfd_create(c_name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(format!("Error creating memfd: {}", last_error()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut segment = Segment::map(fd.as_raw_fd(), size, true, true)?;
+        segment.fd = Some(fd);
+        Ok(segment)
+    }
+
+    pub(crate) fn from_fd(fd: OwnedFd, size: usize) -> Result<Self, String> {
+        let mut segment = Segment::map(fd.as_raw_fd(), size, false, true)?;
+        segment.fd = Some(fd);
+        Ok(segment)
+    }
+
+    fn map(fd: RawFd, size: usize, create: bool, writable: bool) -> Result<Self, String> {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            return Err(format!("Error reading shared memory: {}", last_error()));
+        }
+
+        let mut file_size = stat.st_size as usize;
+        if file_size < size {
+            if !create {
+                return Err(String::from("Shared memory is not a group"));
+            }
+            file_size = size.div_ceil(block_size(fd)) * block_size(fd);
+            if unsafe { libc::ftruncate(fd, file_size as libc::off_t) } < 0 {
+                return Err(format!("Cannot truncate: {}", last_error()));
+            }
+        }
+
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                file_size,
+                prot,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!("Error, mmap failed: {}", last_error()));
+        }
+
+        Ok(Segment {
+            ptr,
+            size: file_size,
+            fd: None,
+            owner_uid: stat.st_uid,
+        })
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut libc::c_void {
+        self.ptr
+    }
+
+    pub(crate) fn get_owner_uid(&self) -> u32 {
+        self.owner_uid
+    }
+
+    // Only anonymous segments keep their descriptor
+    pub(crate) fn get_fd(&self) -> Option<RawFd> {
+        self.fd.as_ref().map(|fd| fd.as_raw_fd())
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.size);
+        }
+    }
+}
+
+pub(crate) fn unlink(root: Option<&Path>, name: &str) -> Result<(), String> {
+    let ret = match root {
+        None => {
+            let c_name = c_string(name)?;
+            unsafe { libc::shm_unlink(c_name.as_ptr()) }
+        }
+        Some(root) => {
+            let c_path = c_string(&root.join(name).to_string_lossy())?;
+            unsafe { libc::unlink(c_path.as_ptr()) }
+        }
+    };
+    if ret < 0 {
+        return Err(String::from("Error unlinking shared memory"));
+    }
+    Ok(())
+}
+
+fn dup_fd(fd: RawFd, command: i32) -> Result<OwnedFd, String> {
+    let ret = unsafe { libc::fcntl(fd, command, 0) };
+    if ret < 0 {
+        return Err(format!("Error duplicating descriptor: {}", last_error()));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(ret) })
+}
+
+// Duplicates the descriptor without close-on-exec so children inherit it
+pub(crate) fn inheritable_fd(fd: RawFd) -> Result<OwnedFd, String> {
+    dup_fd(fd, libc::F_DUPFD)
+}
+
+// Duplicates the descriptor for use within this process only
+pub(crate) fn private_fd(fd: RawFd) -> Result<OwnedFd, String> {
+    dup_fd(fd, libc::F_DUPFD_CLOEXEC)
+}
+
+pub(crate) fn send_fd(socket: &UnixStream, fd: RawFd) -> Result<(), String> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+
+    let ret = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if ret < 0 {
+        return Err(format!("Error sending descriptor: {}", last_error()));
+    }
+    Ok(())
+}
+
+// User of the process on the other end of the socket
+pub(crate) fn peer_uid(socket: &UnixStream) -> Result<u32, String> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(format!("Error reading peer credentials: {}", last_error()));
+    }
+    Ok(cred.uid)
+}
+
+pub(crate) fn recv_fd(socket: &UnixStream) -> Result<OwnedFd, String> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let ret = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if ret <= 0 {
+        return Err(format!("Error receiving descriptor: {}", last_error()));
+    }
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(String::from("No descriptor received"));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
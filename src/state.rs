@@ -0,0 +1,345 @@
+use crate::hash::fnv1a;
+use crate::segment::Segment;
+use crate::WAKE_ALL;
+use crate::{MAX_STATE_CHANNEL_NAME_SIZE, MAX_STATE_SIZE};
+
+use rufutex::rufutex::SharedFutex;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::rc::Rc;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+
+// C representation
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Channel {
+    id: u64,
+    type_size: u64,
+    type_hash: u64,
+    sequence: u32,
+    name: [u8; MAX_STATE_CHANNEL_NAME_SIZE],
+    data: [u64; MAX_STATE_SIZE / 8],
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Channel {
+            id: 0,
+            type_size: 0,
+            type_hash: 0,
+            sequence: 0,
+            name: [0; MAX_STATE_CHANNEL_NAME_SIZE],
+            data: [0; MAX_STATE_SIZE / 8],
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        let vname: Vec<u8> = self.name.iter().take_while(|&&c| c != 0).cloned().collect();
+        String::from_utf8(vname).unwrap()
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_type_size(&self) -> u64 {
+        self.type_size
+    }
+
+    pub fn get_type_hash(&self) -> u64 {
+        self.type_hash
+    }
+
+    // Even values count publications, odd values mean a write is in progress
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    pub(crate) fn init<T: StateType>(&mut self, id: u64, name: &str) -> Result<(), &'static str> {
+        if name.len() >= MAX_STATE_CHANNEL_NAME_SIZE {
+            return Err("Name too long");
+        }
+        *self = Channel::new();
+        self.id = id;
+        self.type_size = std::mem::size_of::<T>() as u64;
+        self.type_hash = type_hash::<T>();
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    pub(crate) fn check_type<T: StateType>(&self) -> Result<(), &'static str> {
+        if self.type_size != std::mem::size_of::<T>() as u64 || self.type_hash != type_hash::<T>() {
+            return Err("State channel type mismatch");
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn type_hash<T: StateType>() -> u64 {
+    let mut bytes = T::TAG.as_bytes().to_vec();
+    bytes.extend_from_slice(&(std::mem::size_of::<T>() as u64).to_le_bytes());
+    bytes.extend_from_slice(&(std::mem::align_of::<T>() as u64).to_le_bytes());
+    fnv1a(&bytes)
+}
+
+pub(crate) fn check_layout<T>() -> Result<(), String> {
+    if std::mem::size_of::<T>() > MAX_STATE_SIZE {
+        return Err(String::from("State type too large"));
+    }
+    if std::mem::align_of::<T>() > std::mem::align_of::<u64>() {
+        return Err(String::from("State type alignment not supported"));
+    }
+    Ok(())
+}
+
+// Type that can be shared through a state channel. It must be a #[repr(C)]
+// plain data type since its bytes are shared as-is between processes.
+//
+// The tag identifies the type across processes, type names given by the
+// compiler are not stable between builds. Give each type its own tag, and a
+// new one when its layout changes, e.g. "Reading/v2".
+pub trait StateType: Copy {
+    const TAG: &'static str;
+}
+
+macro_rules! impl_state_type {
+    ($($t:ty),*) => {
+        $(impl StateType for $t {
+            const TAG: &'static str = stringify!($t);
+        })*
+    };
+}
+
+impl_state_type!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, bool);
+
+// Latest-value channel
+pub struct StateChannel<T: StateType> {
+    // Keeps the mapping holding the channel alive
+    _segment: Rc<Segment>,
+    channel: *mut Channel,
+    futex: SharedFutex,
+    last_sequence: u32,
+    phantom: PhantomData<T>,
+}
+
+impl<T: StateType> StateChannel<T> {
+    pub(crate) fn new(segment: Rc<Segment>, channel: *mut Channel) -> Self {
+        let ptr_sequence = unsafe { std::ptr::addr_of_mut!((*channel).sequence) };
+        StateChannel {
+            _segment: segment,
+            channel,
+            futex: SharedFutex::new(ptr_sequence as *mut libc::c_void),
+            last_sequence: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        unsafe { (*self.channel).get_name() }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        unsafe { (*self.channel).get_id() }
+    }
+
+    fn sequence(&self) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(std::ptr::addr_of_mut!((*self.channel).sequence)) }
+    }
+
+    fn data(&self) -> *mut T {
+        unsafe { std::ptr::addr_of_mut!((*self.channel).data) as *mut T }
+    }
+
+    pub fn publish(&mut self, value: T) {
+        let sequence = self.sequence();
+        let mut current = sequence.load(Ordering::SeqCst);
+        loop {
+            if current & 1 == 1 {
+                std::thread::yield_now();
+                current = sequence.load(Ordering::SeqCst);
+                continue;
+            }
+            match sequence.compare_exchange(
+                current,
+                current.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => break,
+                Err(v) => current = v,
+            }
+        }
+
+        unsafe {
+            std::ptr::write_volatile(self.data(), value);
+        }
+
+        // Zero is reserved for "never published"
+        let mut next = current.wrapping_add(2);
+        if next == 0 {
+            next = 2;
+        }
+        sequence.store(next, Ordering::SeqCst);
+        self.futex.post(WAKE_ALL);
+    }
+
+    fn try_read(&self) -> Option<(T, u32)> {
+        let sequence = self.sequence();
+        loop {
+            let before = sequence.load(Ordering::SeqCst);
+            if before == 0 {
+                return None;
+            }
+            if before & 1 == 1 {
+                std::thread::yield_now();
+                continue;
+            }
+
+            let mut value = MaybeUninit::<T>::uninit();
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    self.data() as *const u8,
+                    value.as_mut_ptr() as *mut u8,
+                    std::mem::size_of::<T>(),
+                );
+            }
+            fence(Ordering::Acquire);
+
+            if sequence.load(Ordering::SeqCst) == before {
+                return Some((unsafe { value.assume_init() }, before));
+            }
+        }
+    }
+
+    // Latest published value, None if nothing was published yet
+    pub fn read(&self) -> Option<T> {
+        self.try_read().map(|(value, _)| value)
+    }
+
+    // Blocks until a value newer than the last one returned by this handle is published
+    pub fn wait_changed(&mut self) -> T {
+        loop {
+            let sequence = self.sequence().load(Ordering::SeqCst);
+            if sequence != 0 && sequence & 1 == 0 && sequence != self.last_sequence {
+                if let Some((value, read_sequence)) = self.try_read() {
+                    self.last_sequence = read_sequence;
+                    return value;
+                }
+            }
+            self.futex.wait(sequence);
+        }
+    }
+
+    pub fn wait_changed_timeout(&mut self, timeout: std::time::Duration) -> Option<T> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let sequence = self.sequence().load(Ordering::SeqCst);
+            if sequence != 0 && sequence & 1 == 0 && sequence != self.last_sequence {
+                if let Some((value, read_sequence)) = self.try_read() {
+                    self.last_sequence = read_sequence;
+                    return Some(value);
+                }
+            }
+
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let remaining = deadline - now;
+            let timeout_spec = libc::timespec {
+                tv_sec: remaining.as_secs() as i64,
+                tv_nsec: remaining.subsec_nanos() as i64,
+            };
+            self.futex.wait_with_timeout(sequence, timeout_spec);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[cfg(test)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Reading {
+    sensor: u32,
+    value: f64,
+}
+
+#[cfg(test)]
+impl StateType for Reading {
+    const TAG: &'static str = "Reading/v1";
+}
+
+// Same layout as Reading
+#[cfg(test)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Humidity {
+    sensor: u32,
+    percent: f64,
+}
+
+#[cfg(test)]
+impl StateType for Humidity {
+    const TAG: &'static str = "Humidity/v1";
+}
+
+#[cfg(test)]
+impl StateType for [u8; 1024] {
+    const TAG: &'static str = "[u8; 1024]";
+}
+
+#[test]
+fn test_state_channel() {
+    let mut coordinator = Coordinator::new("test_state_channel").unwrap();
+    let mut publisher = coordinator.state_channel::<Reading>("temperature").unwrap();
+    let mut reader = coordinator.state_channel::<Reading>("temperature").unwrap();
+    assert_eq!(coordinator.get_number_of_state_channels(), 1);
+    assert_eq!(reader.read(), None);
+    assert!(reader
+        .wait_changed_timeout(std::time::Duration::from_millis(10))
+        .is_none());
+
+    publisher.publish(Reading {
+        sensor: 1,
+        value: 21.5,
+    });
+    assert_eq!(reader.read().unwrap().value, 21.5);
+    assert_eq!(reader.wait_changed().value, 21.5);
+
+    let handle = std::thread::spawn(move || {
+        let mut coordinator = Coordinator::open_existing("test_state_channel").unwrap();
+        let mut publisher = coordinator.state_channel::<Reading>("temperature").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        publisher.publish(Reading {
+            sensor: 1,
+            value: 22.0,
+        });
+    });
+
+    assert_eq!(reader.wait_changed().value, 22.0);
+    handle.join().unwrap();
+
+    let _ = coordinator.close(true);
+}
+
+#[test]
+fn test_state_channel_type_mismatch() {
+    let mut coordinator = Coordinator::new("test_state_channel_type_mismatch").unwrap();
+    assert!(coordinator.state_channel::<u64>("position").is_ok());
+    assert!(coordinator.state_channel::<i64>("position").is_err());
+    assert!(coordinator.state_channel::<u32>("position").is_err());
+    assert!(coordinator.state_channel::<[u8; 1024]>("large").is_err());
+
+    assert!(coordinator.state_channel::<Reading>("sensor").is_ok());
+    assert!(coordinator.state_channel::<Humidity>("sensor").is_err());
+
+    let _ = coordinator.close(true);
+}
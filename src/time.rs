@@ -0,0 +1,44 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// RFC 3339 in UTC with milliseconds
+pub(crate) fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+
+    // Civil date from days since the epoch, see "chrono-Compatible Low-Level
+    // Date Algorithms" by Howard Hinnant
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+use std::time::Duration;
+
+#[test]
+fn test_format_time() {
+    assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    assert_eq!(
+        format_time(UNIX_EPOCH + Duration::from_millis(1_709_210_096_789)),
+        "2024-02-29T12:34:56.789Z"
+    );
+}
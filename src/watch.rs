@@ -0,0 +1,416 @@
+use crate::coordinator::Participant;
+use crate::event::Event;
+use crate::group::GroupOptions;
+use crate::observer::Observer;
+use crate::time::format_time;
+use crate::NO_PARTICIPANT;
+
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchEventKind {
+    ParticipantJoined,
+    ParticipantLeft,
+    EventCreated,
+    EventTriggered,
+}
+
+impl WatchEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatchEventKind::ParticipantJoined => "participant_joined",
+            WatchEventKind::ParticipantLeft => "participant_left",
+            WatchEventKind::EventCreated => "event_created",
+            WatchEventKind::EventTriggered => "event_triggered",
+        }
+    }
+}
+
+// Change seen in a group. Event records carry the owner of the event as
+// participant, the process triggering it is not known.
+#[derive(Debug, Clone)]
+pub struct WatchEvent {
+    time: SystemTime,
+    kind: WatchEventKind,
+    participant_id: u64,
+    participant_name: String,
+    pid: u32,
+    event_id: Option<u64>,
+    event_name: String,
+}
+
+impl WatchEvent {
+    fn participant(kind: WatchEventKind, participant: &Participant) -> Self {
+        WatchEvent {
+            time: SystemTime::now(),
+            kind,
+            participant_id: participant.get_id(),
+            participant_name: participant.get_name(),
+            pid: participant.get_pid(),
+            event_id: None,
+            event_name: String::new(),
+        }
+    }
+
+    fn event(kind: WatchEventKind, event: &Event, name: &str, owner: Option<&Participant>) -> Self {
+        WatchEvent {
+            time: SystemTime::now(),
+            kind,
+            participant_id: event.get_owner(),
+            participant_name: owner.map(|p| p.get_name()).unwrap_or_default(),
+            pid: owner.map(|p| p.get_pid()).unwrap_or(0),
+            event_id: Some(event.get_id()),
+            event_name: name.to_string(),
+        }
+    }
+
+    pub fn get_time(&self) -> SystemTime {
+        self.time
+    }
+
+    pub fn get_kind(&self) -> WatchEventKind {
+        self.kind
+    }
+
+    // NO_PARTICIPANT for events without owner
+    pub fn get_participant_id(&self) -> u64 {
+        self.participant_id
+    }
+
+    pub fn get_participant_name(&self) -> String {
+        self.participant_name.clone()
+    }
+
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    // None for participant changes
+    pub fn get_event_id(&self) -> Option<u64> {
+        self.event_id
+    }
+
+    // Name without the group prefix
+    pub fn get_event_name(&self) -> String {
+        self.event_name.clone()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut line = format!("{} {}", format_time(self.time), self.kind.as_str());
+        if let Some(event_id) = self.event_id {
+            line += &format!(" event={}:{}", event_id, self.event_name);
+        }
+        if self.participant_id != NO_PARTICIPANT {
+            line += &format!(
+                " participant={}:{} pid={}",
+                self.participant_id, self.participant_name, self.pid
+            );
+        }
+        line
+    }
+
+    // One JSON object per line
+    pub fn to_json(&self) -> String {
+        let participant_id = if self.participant_id == NO_PARTICIPANT {
+            String::from("null")
+        } else {
+            self.participant_id.to_string()
+        };
+        let event_id = match self.event_id {
+            Some(id) => id.to_string(),
+            None => String::from("null"),
+        };
+        format!(
+            "{{\"time\":\"{}\",\"kind\":\"{}\",\"participant_id\":{},\"participant\":{},\"pid\":{},\"event_id\":{},\"event\":{}}}",
+            format_time(self.time),
+            self.kind.as_str(),
+            participant_id,
+            json_string(&self.participant_name),
+            self.pid,
+            event_id,
+            json_string(&self.event_name)
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// struct futex_waitv of the kernel
+#[repr(C)]
+struct FutexWaitv {
+    val: u64,
+    uaddr: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+const FUTEX2_SIZE_U32: u32 = 0x02;
+
+// Blocks until one of the words is woken or no longer holds its value. Falls
+// back to sleeping on kernels without futex_waitv (before 5.16).
+fn wait_any(words: &[(*const u32, u32)], timeout: Duration) {
+    let waiters: Vec<FutexWaitv> = words
+        .iter()
+        .map(|(word, value)| FutexWaitv {
+            val: *value as u64,
+            uaddr: *word as u64,
+            flags: FUTEX2_SIZE_U32,
+            reserved: 0,
+        })
+        .collect();
+
+    // The timeout is absolute
+    let mut deadline: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut deadline) };
+    let nanos = deadline.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+    deadline.tv_sec += timeout.as_secs() as i64 + (nanos / 1_000_000_000) as i64;
+    deadline.tv_nsec = (nanos % 1_000_000_000) as i64;
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex_waitv,
+            waiters.as_ptr(),
+            waiters.len() as u32,
+            0u32,
+            &deadline as *const libc::timespec,
+            libc::CLOCK_MONOTONIC,
+        )
+    };
+    if ret < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ENOSYS) {
+        std::thread::sleep(timeout);
+    }
+}
+
+// Streams the changes of a group: participants joining and leaving, events
+// being created and triggered. It maps the group read-only like an Observer.
+//
+// Waits on the builtin notifications and the generation word of every event.
+// Departures are not notified, so the directory is also checked every poll
+// interval for participants whose process is gone.
+pub struct Watcher {
+    observer: Observer,
+    prefix: String,
+    participants: Vec<(Participant, bool)>,
+    events: Vec<Event>,
+    poll_interval: Duration,
+}
+
+impl Watcher {
+    pub fn open(mem_path: &str, options: &GroupOptions) -> Result<Self, String> {
+        let observer = Observer::open(mem_path, options)?;
+        let participants = observer.participants().map(|p| (p, p.is_alive())).collect();
+        let events = observer.events().collect();
+
+        Ok(Watcher {
+            observer,
+            prefix: mem_path.to_string() + "_",
+            participants,
+            events,
+            poll_interval: Duration::from_millis(500),
+        })
+    }
+
+    // How often departures are checked for, 500ms by default
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+
+    pub fn get_poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    fn owner(&self, event: &Event) -> Option<&Participant> {
+        self.participants
+            .iter()
+            .map(|(p, _)| p)
+            .find(|p| p.get_id() == event.get_owner())
+    }
+
+    fn event_name(&self, event: &Event) -> String {
+        let name = event.get_name();
+        name.strip_prefix(&self.prefix).unwrap_or(&name).to_string()
+    }
+
+    // Changes since the previous call, without blocking
+    pub fn poll(&mut self) -> Vec<WatchEvent> {
+        let mut changes = Vec::new();
+
+        for participant in self.observer.participants() {
+            let alive = participant.is_alive();
+            let id = participant.get_id() as usize;
+            if id >= self.participants.len() {
+                changes.push(WatchEvent::participant(
+                    WatchEventKind::ParticipantJoined,
+                    &participant,
+                ));
+                self.participants.push((participant, alive));
+                continue;
+            }
+
+            // A slot taken over by a new process after a restart
+            let (known, was_alive) = self.participants[id];
+            if alive && (!was_alive || known.get_pid() != participant.get_pid()) {
+                changes.push(WatchEvent::participant(
+                    WatchEventKind::ParticipantJoined,
+                    &participant,
+                ));
+            } else if !alive && was_alive {
+                changes.push(WatchEvent::participant(
+                    WatchEventKind::ParticipantLeft,
+                    &participant,
+                ));
+            }
+            self.participants[id] = (participant, alive);
+        }
+
+        for event in self.observer.events() {
+            let id = event.get_id() as usize;
+            let name = self.event_name(&event);
+            if id >= self.events.len() {
+                changes.push(WatchEvent::event(
+                    WatchEventKind::EventCreated,
+                    &event,
+                    &name,
+                    self.owner(&event),
+                ));
+                self.events.push(event);
+                continue;
+            }
+
+            let triggers = event
+                .get_generation()
+                .wrapping_sub(self.events[id].get_generation());
+            for _ in 0..triggers {
+                changes.push(WatchEvent::event(
+                    WatchEventKind::EventTriggered,
+                    &event,
+                    &name,
+                    self.owner(&event),
+                ));
+            }
+            self.events[id] = event;
+        }
+
+        changes
+    }
+
+    // Blocks until something changes in the group. Returns an empty list if
+    // the timeout expired first.
+    pub fn next_events(&mut self, timeout: Option<Duration>) -> Vec<WatchEvent> {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            // Read before checking the directory so no notification is lost
+            let directory = self.observer.directory();
+            let mut words: Vec<(*const u32, u32)> = directory
+                .get_builtin_words()
+                .iter()
+                .map(|word| (word as *const u32, *word))
+                .collect();
+
+            let changes = self.poll();
+            if !changes.is_empty() {
+                return changes;
+            }
+
+            let directory = self.observer.directory();
+            for known in &self.events {
+                if let Some(event) = directory.get_event(known.get_id()) {
+                    let generation = Event::generation_atom(event);
+                    words.push((generation.as_ptr(), known.get_generation()));
+                }
+            }
+
+            let mut wait = self.poll_interval;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return changes;
+                }
+                wait = wait.min(deadline - now);
+            }
+            wait_any(&words, wait);
+        }
+    }
+
+    // Calls `f` with every change until it returns false
+    pub fn run(&mut self, mut f: impl FnMut(&WatchEvent) -> bool) {
+        loop {
+            for change in self.next_events(None) {
+                if !f(&change) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::coordinator::Coordinator;
+
+#[test]
+fn test_watcher() {
+    let mut coordinator = Coordinator::new("test_watcher").unwrap();
+    let mut watcher = Watcher::open("test_watcher", &GroupOptions::new()).unwrap();
+    watcher.set_poll_interval(Duration::from_millis(10));
+    assert!(watcher
+        .next_events(Some(Duration::from_millis(10)))
+        .is_empty());
+
+    let participant_id = coordinator.add_participant("test_publisher").unwrap();
+    let mut event = coordinator.add_event(participant_id, "test_event").unwrap();
+    let changes = watcher.next_events(Some(Duration::from_secs(1)));
+    let kinds: Vec<WatchEventKind> = changes.iter().map(|c| c.get_kind()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WatchEventKind::ParticipantJoined,
+            WatchEventKind::EventCreated
+        ]
+    );
+    assert_eq!(changes[1].get_event_name(), "test_event");
+    assert_eq!(changes[1].get_participant_name(), "test_publisher");
+
+    let handle = std::thread::spawn(move || {
+        let mut watcher = Watcher::open("test_watcher", &GroupOptions::new()).unwrap();
+        watcher.set_poll_interval(Duration::from_secs(10));
+        let start = Instant::now();
+        let changes = watcher.next_events(Some(Duration::from_secs(10)));
+        (changes, start.elapsed())
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    event.trigger(1).unwrap();
+    let (changes, elapsed) = handle.join().unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].get_kind(), WatchEventKind::EventTriggered);
+    // Woken by the trigger rather than the poll interval
+    assert!(elapsed < Duration::from_secs(5));
+    assert!(changes[0].to_json().contains("\"event\":\"test_event\""));
+
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+    coordinator.set_participant_pid(participant_id, dead_pid);
+    let changes = watcher.next_events(Some(Duration::from_secs(1)));
+    let kinds: Vec<WatchEventKind> = changes.iter().map(|c| c.get_kind()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WatchEventKind::ParticipantLeft,
+            WatchEventKind::EventTriggered
+        ]
+    );
+
+    let _ = coordinator.close(true);
+}